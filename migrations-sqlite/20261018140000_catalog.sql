@@ -0,0 +1,40 @@
+-- The catalog for riverbank deployments which keep it in SQLite
+--
+-- Ids are stored as 16 byte blobs and timestamps as text, which is how sqlx
+-- encodes them for SQLite
+
+CREATE TABLE shares (
+    id BLOB PRIMARY KEY NOT NULL,
+    name TEXT NOT NULL,
+    created_at TEXT NOT NULL
+);
+
+CREATE TABLE schemas (
+    id BLOB PRIMARY KEY NOT NULL,
+    name TEXT NOT NULL,
+    share_id BLOB NOT NULL REFERENCES shares(id),
+    created_at TEXT NOT NULL
+);
+
+CREATE TABLE tables (
+    id BLOB PRIMARY KEY NOT NULL,
+    name TEXT NOT NULL,
+    location TEXT NOT NULL,
+    schema_id BLOB NOT NULL REFERENCES schemas(id),
+    created_at TEXT NOT NULL
+);
+
+CREATE TABLE tokens (
+    id BLOB PRIMARY KEY NOT NULL,
+    name TEXT NOT NULL,
+    token TEXT NOT NULL UNIQUE,
+    expires_at TEXT NOT NULL,
+    created_at TEXT NOT NULL
+);
+
+CREATE TABLE tokens_for_tables (
+    token_id BLOB NOT NULL REFERENCES tokens(id),
+    table_id BLOB NOT NULL REFERENCES tables(id),
+    created_at TEXT NOT NULL,
+    PRIMARY KEY (token_id, table_id)
+);
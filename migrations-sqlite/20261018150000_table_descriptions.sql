@@ -0,0 +1,3 @@
+-- An optional description of a shared table, which is handed to recipients
+-- in place of the one in the Delta table's metadata
+ALTER TABLE tables ADD COLUMN description TEXT;
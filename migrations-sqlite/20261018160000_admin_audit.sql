@@ -0,0 +1,26 @@
+-- Append-only trail of every change made to the catalog, with the old and
+-- new values stored as JSON text
+
+CREATE TABLE admin_audit (
+    id BLOB PRIMARY KEY NOT NULL,
+    actor TEXT NOT NULL,
+    action TEXT NOT NULL,
+    object_type TEXT NOT NULL,
+    object_id BLOB,
+    old_value TEXT,
+    new_value TEXT,
+    source_ip TEXT,
+    created_at TEXT NOT NULL
+);
+
+CREATE INDEX admin_audit_object ON admin_audit (object_type, object_id);
+
+-- Entries must never be changed or removed once written
+CREATE TRIGGER admin_audit_no_update BEFORE UPDATE ON admin_audit
+BEGIN
+    SELECT RAISE(ABORT, 'The audit trail cannot be changed');
+END;
+CREATE TRIGGER admin_audit_no_delete BEFORE DELETE ON admin_audit
+BEGIN
+    SELECT RAISE(ABORT, 'The audit trail cannot be changed');
+END;
@@ -0,0 +1,24 @@
+-- Append-only record of every request made by a recipient to the sharing API
+
+CREATE TABLE access_log (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    token_id UUID NOT NULL,
+    operation TEXT NOT NULL,
+    share_name TEXT,
+    schema_name TEXT,
+    table_name TEXT,
+    table_version BIGINT,
+    predicate_hints JSONB,
+    num_files BIGINT,
+    num_bytes BIGINT,
+    client_ip TEXT,
+    user_agent TEXT,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW() NOT NULL,
+    CONSTRAINT fk_token FOREIGN KEY(token_id) REFERENCES tokens(id)
+);
+
+CREATE INDEX access_log_created_at ON access_log (created_at);
+
+-- Entries must never be changed or removed once written
+CREATE RULE access_log_no_update AS ON UPDATE TO access_log DO INSTEAD NOTHING;
+CREATE RULE access_log_no_delete AS ON DELETE TO access_log DO INSTEAD NOTHING;
@@ -0,0 +1,19 @@
+-- Append-only trail of every change made through the admin console
+
+CREATE TABLE admin_audit (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    actor TEXT NOT NULL,
+    action TEXT NOT NULL,
+    object_type TEXT NOT NULL,
+    object_id UUID,
+    old_value JSONB,
+    new_value JSONB,
+    source_ip TEXT,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW() NOT NULL
+);
+
+CREATE INDEX admin_audit_object ON admin_audit (object_type, object_id);
+
+-- Entries must never be changed or removed once written
+CREATE RULE admin_audit_no_update AS ON UPDATE TO admin_audit DO INSTEAD NOTHING;
+CREATE RULE admin_audit_no_delete AS ON DELETE TO admin_audit DO INSTEAD NOTHING;
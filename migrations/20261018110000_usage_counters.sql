@@ -0,0 +1,12 @@
+-- Counters for enforcing the rate limits and quotas of the sharing API, one
+-- row per subject (token or recipient) and window of time
+
+CREATE TABLE usage_counters (
+    subject TEXT NOT NULL,
+    period TEXT NOT NULL,
+    window_start TIMESTAMP WITH TIME ZONE NOT NULL,
+    requests BIGINT DEFAULT 0 NOT NULL,
+    queries BIGINT DEFAULT 0 NOT NULL,
+    bytes BIGINT DEFAULT 0 NOT NULL,
+    PRIMARY KEY (subject, period, window_start)
+);
@@ -0,0 +1,12 @@
+-- The latest state of every table as seen by the background table watcher
+
+CREATE TABLE table_status (
+    table_id UUID PRIMARY KEY REFERENCES tables(id) ON DELETE CASCADE,
+    version BIGINT,
+    commit_timestamp TIMESTAMP WITH TIME ZONE,
+    num_files BIGINT,
+    size_bytes BIGINT,
+    healthy BOOLEAN NOT NULL,
+    error TEXT,
+    checked_at TIMESTAMP WITH TIME ZONE DEFAULT NOW() NOT NULL
+);
@@ -121,6 +121,161 @@ CREATE TABLE public.tokens_for_tables (
 
 ALTER TABLE public.tokens_for_tables OWNER TO postgres;
 
+--
+-- Name: access_log; Type: TABLE; Schema: public; Owner: postgres
+--
+
+CREATE TABLE public.access_log (
+    id uuid DEFAULT public.gen_random_uuid() NOT NULL,
+    token_id uuid NOT NULL,
+    operation text NOT NULL,
+    share_name text,
+    schema_name text,
+    table_name text,
+    table_version bigint,
+    predicate_hints jsonb,
+    num_files bigint,
+    num_bytes bigint,
+    client_ip text,
+    user_agent text,
+    created_at timestamp with time zone DEFAULT now() NOT NULL
+);
+
+
+ALTER TABLE public.access_log OWNER TO postgres;
+
+ALTER TABLE ONLY public.access_log
+    ADD CONSTRAINT access_log_pkey PRIMARY KEY (id);
+
+CREATE INDEX access_log_created_at ON public.access_log USING btree (created_at);
+
+CREATE RULE access_log_no_update AS
+    ON UPDATE TO public.access_log DO INSTEAD NOTHING;
+
+CREATE RULE access_log_no_delete AS
+    ON DELETE TO public.access_log DO INSTEAD NOTHING;
+
+
+--
+-- Name: admin_audit; Type: TABLE; Schema: public; Owner: postgres
+--
+
+CREATE TABLE public.admin_audit (
+    id uuid DEFAULT public.gen_random_uuid() NOT NULL,
+    actor text NOT NULL,
+    action text NOT NULL,
+    object_type text NOT NULL,
+    object_id uuid,
+    old_value jsonb,
+    new_value jsonb,
+    source_ip text,
+    created_at timestamp with time zone DEFAULT now() NOT NULL
+);
+
+
+ALTER TABLE public.admin_audit OWNER TO postgres;
+
+ALTER TABLE ONLY public.admin_audit
+    ADD CONSTRAINT admin_audit_pkey PRIMARY KEY (id);
+
+CREATE INDEX admin_audit_object ON public.admin_audit USING btree (object_type, object_id);
+
+CREATE RULE admin_audit_no_update AS
+    ON UPDATE TO public.admin_audit DO INSTEAD NOTHING;
+
+CREATE RULE admin_audit_no_delete AS
+    ON DELETE TO public.admin_audit DO INSTEAD NOTHING;
+
+
+--
+-- Name: usage_counters; Type: TABLE; Schema: public; Owner: postgres
+--
+
+CREATE TABLE public.usage_counters (
+    subject text NOT NULL,
+    period text NOT NULL,
+    window_start timestamp with time zone NOT NULL,
+    requests bigint DEFAULT 0 NOT NULL,
+    queries bigint DEFAULT 0 NOT NULL,
+    bytes bigint DEFAULT 0 NOT NULL
+);
+
+
+ALTER TABLE public.usage_counters OWNER TO postgres;
+
+ALTER TABLE ONLY public.usage_counters
+    ADD CONSTRAINT usage_counters_pkey PRIMARY KEY (subject, period, window_start);
+
+
+--
+-- Name: table_status; Type: TABLE; Schema: public; Owner: postgres
+--
+
+CREATE TABLE public.table_status (
+    table_id uuid NOT NULL,
+    version bigint,
+    commit_timestamp timestamp with time zone,
+    num_files bigint,
+    size_bytes bigint,
+    healthy boolean NOT NULL,
+    error text,
+    checked_at timestamp with time zone DEFAULT now() NOT NULL
+);
+
+
+ALTER TABLE public.table_status OWNER TO postgres;
+
+ALTER TABLE ONLY public.table_status
+    ADD CONSTRAINT table_status_pkey PRIMARY KEY (table_id);
+
+
+--
+-- Name: webhooks; Type: TABLE; Schema: public; Owner: postgres
+--
+
+CREATE TABLE public.webhooks (
+    id uuid DEFAULT public.gen_random_uuid() NOT NULL,
+    recipient text NOT NULL,
+    url text NOT NULL,
+    secret text NOT NULL,
+    created_at timestamp with time zone DEFAULT now() NOT NULL
+);
+
+
+ALTER TABLE public.webhooks OWNER TO postgres;
+
+ALTER TABLE ONLY public.webhooks
+    ADD CONSTRAINT webhooks_pkey PRIMARY KEY (id);
+
+CREATE INDEX webhooks_recipient ON public.webhooks USING btree (recipient);
+
+--
+-- Name: webhook_deliveries; Type: TABLE; Schema: public; Owner: postgres
+--
+
+CREATE TABLE public.webhook_deliveries (
+    id uuid DEFAULT public.gen_random_uuid() NOT NULL,
+    webhook_id uuid NOT NULL,
+    table_id uuid NOT NULL,
+    payload jsonb NOT NULL,
+    status text DEFAULT 'pending'::text NOT NULL,
+    attempts integer DEFAULT 0 NOT NULL,
+    response_status integer,
+    error text,
+    next_attempt_at timestamp with time zone DEFAULT now() NOT NULL,
+    delivered_at timestamp with time zone,
+    created_at timestamp with time zone DEFAULT now() NOT NULL
+);
+
+
+ALTER TABLE public.webhook_deliveries OWNER TO postgres;
+
+ALTER TABLE ONLY public.webhook_deliveries
+    ADD CONSTRAINT webhook_deliveries_pkey PRIMARY KEY (id);
+
+CREATE INDEX webhook_deliveries_pending ON public.webhook_deliveries USING btree (next_attempt_at) WHERE (status = 'pending'::text);
+
+
 --
 -- Data for Name: _sqlx_migrations; Type: TABLE DATA; Schema: public; Owner: postgres
 --
@@ -129,6 +284,11 @@ COPY public._sqlx_migrations (version, description, installed_on, success, check
 20210530213127	simple tokens	2021-05-30 21:51:02.339722+00	t	\\x169f48de73792104d974bb0793b6cd4ca6dce759e75000a883d5cbc387b291d2838c619eda7362cd4d312d329acf8427	50215984
 20210530215400	location for tables	2021-05-30 21:55:21.35334+00	t	\\xed47e0f38faaab6a4f4dc2f3854f0b55aa4d30d2ffe7dbc603e45bc343a2447ca6e76dbe5d4faed0c58d1836812ac2e3	2154908
 20210531181347	tokens have names	2021-05-31 18:13:47.12345+00	t	\\x338adbcd6080771ac316b3d23a01bbebec15b446795e5441d627121d3e5bb85519fe75a2ce5f0c39ec8b4304fb8b2dcc	40000000
+20261018090000	access log	2026-10-18 09:00:00.000000+00	t	\\x70966da2ea19eb13569abf11d0b3e1ea47611e043c8766bcf5b67671e2bf85be7b4b580ef7a1af13748c2eefbf2f4a8b	10000000
+20261018100000	admin audit	2026-10-18 10:00:00.000000+00	t	\\x75f67846d6096a8cd9f11a73b107b6c80a70a25b152cedcdafbb417922681d690e09ee546bec35dee12cecbe94291d71	10000000
+20261018110000	usage counters	2026-10-18 11:00:00.000000+00	t	\\x3d3923cd887c6f4a3e7f2d00635fb26e24c4835fb6a1630a6ee5aaf1f9c1dd5f5b74a95b06262cdccdcc33dd6c2f5e6a	10000000
+20261018120000	table status	2026-10-18 12:00:00.000000+00	t	\\x79aeeb6cc67e55deadc67b318f317a60c5c7bd2ae8a35df3eee531ff23f6ca9db88f9830e63ea0882017ba72e6887dd7	10000000
+20261018130000	webhooks	2026-10-18 13:00:00.000000+00	t	\\x2ba02240bf20337254e4a8c67a9b6e4cba67f92066b110ebaed14bf6dfa72c0c21007503fa9a29453380e20fc57e7941	10000000
 \.
 
 
@@ -255,6 +415,38 @@ ALTER TABLE ONLY public.tokens_for_tables
     ADD CONSTRAINT fk_token FOREIGN KEY (token_id) REFERENCES public.tokens(id);
 
 
+--
+-- Name: access_log fk_token; Type: FK CONSTRAINT; Schema: public; Owner: postgres
+--
+
+ALTER TABLE ONLY public.access_log
+    ADD CONSTRAINT fk_token FOREIGN KEY (token_id) REFERENCES public.tokens(id);
+
+
+--
+-- Name: table_status table_status_table_id_fkey; Type: FK CONSTRAINT; Schema: public; Owner: postgres
+--
+
+ALTER TABLE ONLY public.table_status
+    ADD CONSTRAINT table_status_table_id_fkey FOREIGN KEY (table_id) REFERENCES public.tables(id) ON DELETE CASCADE;
+
+
+--
+-- Name: webhook_deliveries webhook_deliveries_webhook_id_fkey; Type: FK CONSTRAINT; Schema: public; Owner: postgres
+--
+
+ALTER TABLE ONLY public.webhook_deliveries
+    ADD CONSTRAINT webhook_deliveries_webhook_id_fkey FOREIGN KEY (webhook_id) REFERENCES public.webhooks(id) ON DELETE CASCADE;
+
+
+--
+-- Name: webhook_deliveries webhook_deliveries_table_id_fkey; Type: FK CONSTRAINT; Schema: public; Owner: postgres
+--
+
+ALTER TABLE ONLY public.webhook_deliveries
+    ADD CONSTRAINT webhook_deliveries_table_id_fkey FOREIGN KEY (table_id) REFERENCES public.tables(id) ON DELETE CASCADE;
+
+
 --
 -- PostgreSQL database dump complete
 --
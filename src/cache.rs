@@ -0,0 +1,636 @@
+/*
+ * The cache module keeps recently loaded Delta tables in memory, so that
+ * requests for hot tables don't need to re-read the whole transaction log
+ */
+use async_std::sync::{Arc, Mutex};
+use deltalake::action::Add;
+use deltalake::{DeltaTable, DeltaTableError, DeltaTableMetaData, StorageBackend};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::config::TableCacheConfig;
+use crate::metrics;
+
+/**
+ * An immutable view of a Delta table at a single version, which can be shared
+ * between concurrent requests
+ */
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    pub table_uri: String,
+    pub version: i64,
+    /// Seconds since the epoch at which the version was committed
+    pub commit_timestamp: Option<i64>,
+    pub min_reader_version: i32,
+    pub min_writer_version: i32,
+    pub metadata: DeltaTableMetaData,
+    pub files: Vec<Add>,
+    /// The number of data files, counted once per version
+    pub num_files: i64,
+    /// The total size of the data files in bytes
+    pub size: i64,
+    /// The reader features which clients must support to read this version
+    pub reader_features: Vec<String>,
+}
+
+impl Snapshot {
+    pub async fn from_table(delta: &mut DeltaTable) -> Result<Snapshot, DeltaTableError> {
+        let version = delta.version;
+        let files = delta.get_actions().to_vec();
+        let metadata = delta.get_metadata()?.clone();
+        Ok(Snapshot {
+            table_uri: delta.table_uri.clone(),
+            version,
+            commit_timestamp: delta.get_version_timestamp(version).await.ok(),
+            min_reader_version: delta.get_min_reader_version(),
+            min_writer_version: delta.get_min_writer_version(),
+            reader_features: match protocol_reader_features(&delta.table_uri, version).await {
+                Some(features) => features,
+                None => crate::capabilities::required_reader_features(&metadata),
+            },
+            metadata,
+            num_files: files.len() as i64,
+            size: files.iter().map(|add| add.size).sum(),
+            files,
+        })
+    }
+
+    /**
+     * A rough estimate of the memory used by the snapshot, which is dominated
+     * by the paths and statistics of the data files
+     */
+    fn estimated_size(&self) -> usize {
+        self.files
+            .iter()
+            .map(|add| {
+                std::mem::size_of::<Add>()
+                    + add.path.len()
+                    + add.stats.as_ref().map_or(0, String::len)
+                    // Partition values tend to be about as long as their names
+                    + 2 * add.partition_values.keys().map(String::len).sum::<usize>()
+            })
+            .sum()
+    }
+}
+
+#[derive(Deserialize)]
+struct LastCheckpoint {
+    version: i64,
+}
+
+#[derive(Deserialize)]
+struct Action {
+    protocol: Option<ProtocolAction>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ProtocolAction {
+    min_reader_version: i32,
+    reader_features: Option<Vec<String>>,
+}
+
+/**
+ * The `readerFeatures` of the newest protocol action in the commits since the
+ * last checkpoint, which the Delta library does not read.
+ *
+ * None when the protocol predates reader features, or when it can only be
+ * found in the checkpoint, in which case the features have to be inferred
+ */
+async fn protocol_reader_features(table_uri: &str, version: i64) -> Option<Vec<String>> {
+    let storage = deltalake::get_backend_for_uri(table_uri).ok()?;
+    let log = format!("{}/_delta_log", table_uri.trim_end_matches('/'));
+    let checkpoint = match storage.get_obj(&format!("{}/_last_checkpoint", log)).await {
+        Ok(contents) => serde_json::from_slice::<LastCheckpoint>(&contents)
+            .map(|checkpoint| checkpoint.version)
+            .unwrap_or(-1),
+        Err(_) => -1,
+    };
+
+    for commit in (checkpoint + 1..=version).rev() {
+        let contents = storage
+            .get_obj(&format!("{}/{:020}.json", log, commit))
+            .await
+            .ok()?;
+        let protocol = String::from_utf8_lossy(&contents)
+            .lines()
+            .filter_map(|line| serde_json::from_str::<Action>(line).ok())
+            .filter_map(|action| action.protocol)
+            .last();
+        if let Some(protocol) = protocol {
+            return protocol
+                .reader_features
+                .filter(|_| protocol.min_reader_version >= 3);
+        }
+    }
+    None
+}
+
+/**
+ * Count a lookup or eviction both in the stats of the cache and in the
+ * Prometheus metrics
+ */
+fn count(counter: &AtomicU64, result: &str) {
+    counter.fetch_add(1, Ordering::Relaxed);
+    metrics::TABLE_CACHE.with_label_values(&[result]).inc();
+}
+
+/**
+ * A cached table keeps the DeltaTable itself around for incremental updates,
+ * next to the snapshot which is handed out to requests
+ */
+#[derive(Debug)]
+struct CachedTable {
+    delta: DeltaTable,
+    snapshot: Arc<Snapshot>,
+    refreshed_at: Instant,
+}
+
+#[derive(Debug)]
+struct Slot {
+    table: Arc<Mutex<CachedTable>>,
+    size: usize,
+    last_used: Instant,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub bytes: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub updates: u64,
+    pub evictions: u64,
+}
+
+/**
+ * TableCache holds the most recently used tables keyed by their location.
+ *
+ * Tables are refreshed with an incremental update once they are older than
+ * the configured TTL, and the least recently used tables are evicted when
+ * there are too many entries or they use too much memory.
+ *
+ * Concurrent misses for the same table wait for the first one to load it,
+ * unless the table is too large to be cached, in which case each of them
+ * loads it in turn
+ */
+#[derive(Debug)]
+pub struct TableCache {
+    config: TableCacheConfig,
+    slots: std::sync::Mutex<HashMap<String, Slot>>,
+    /// The tables being loaded after a miss, keyed by their location
+    loading: std::sync::Mutex<HashMap<String, Arc<Mutex<()>>>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    updates: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl TableCache {
+    pub fn new(config: TableCacheConfig) -> TableCache {
+        TableCache {
+            config,
+            slots: std::sync::Mutex::new(HashMap::new()),
+            loading: std::sync::Mutex::new(HashMap::new()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            updates: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    /**
+     * Return a snapshot of the latest version of the table at the location,
+     * at most the configured TTL old
+     */
+    pub async fn load(&self, location: &str) -> Result<Arc<Snapshot>, DeltaTableError> {
+        let cached = match self.cached(location) {
+            Some(cached) => cached,
+            None => {
+                let loading = self
+                    .loading
+                    .lock()
+                    .unwrap()
+                    .entry(location.to_string())
+                    .or_insert_with(|| Arc::new(Mutex::new(())))
+                    .clone();
+                let _loading = loading.lock().await;
+
+                match self.cached(location) {
+                    Some(cached) => cached,
+                    None => {
+                        let loaded = self.load_uncached(location).await;
+                        self.loading.lock().unwrap().remove(location);
+                        return loaded;
+                    }
+                }
+            }
+        };
+
+        count(&self.hits, "hit");
+        let mut table = cached.lock().await;
+
+        if table.refreshed_at.elapsed() >= Duration::from_secs(self.config.ttl_seconds) {
+            count(&self.updates, "update");
+            TableCache::refresh(location, &mut table).await?;
+        }
+
+        let snapshot = table.snapshot.clone();
+        drop(table);
+        self.touch(location, &snapshot);
+        Ok(snapshot)
+    }
+
+    /**
+     * Load the table after a miss, caching it when it is within bounds
+     */
+    async fn load_uncached(&self, location: &str) -> Result<Arc<Snapshot>, DeltaTableError> {
+        count(&self.misses, "miss");
+        debug!("Table cache miss for {}", location);
+        let mut delta = deltalake::open_table(location).await?;
+        let snapshot = Arc::new(Snapshot::from_table(&mut delta).await?);
+        self.insert(
+            location,
+            CachedTable {
+                delta,
+                snapshot: snapshot.clone(),
+                refreshed_at: Instant::now(),
+            },
+        );
+        Ok(snapshot)
+    }
+
+    fn cached(&self, location: &str) -> Option<Arc<Mutex<CachedTable>>> {
+        self.slots
+            .lock()
+            .unwrap()
+            .get(location)
+            .map(|slot| slot.table.clone())
+    }
+
+    /**
+     * Return a snapshot of the latest version of the table at the location
+     * for a background check, which refreshes the table in place if it is
+     * cached, but neither counts as a use of the cache nor adds to it
+     */
+    pub async fn check(&self, location: &str) -> Result<Arc<Snapshot>, DeltaTableError> {
+        let cached = match self.cached(location) {
+            Some(cached) => cached,
+            None => {
+                let mut delta = deltalake::open_table(location).await?;
+                return Ok(Arc::new(Snapshot::from_table(&mut delta).await?));
+            }
+        };
+
+        let mut table = cached.lock().await;
+        TableCache::refresh(location, &mut table).await?;
+        let snapshot = table.snapshot.clone();
+        drop(table);
+        self.resize(location, &snapshot);
+        Ok(snapshot)
+    }
+
+    /**
+     * Update the cached table to its latest version
+     */
+    async fn refresh(location: &str, table: &mut CachedTable) -> Result<(), DeltaTableError> {
+        let version = table.delta.version;
+        table.delta.update().await?;
+        table.refreshed_at = Instant::now();
+
+        if table.delta.version != version {
+            debug!(
+                "Updated cached table {} from version {} to {}",
+                location, version, table.delta.version
+            );
+            table.snapshot = Arc::new(Snapshot::from_table(&mut table.delta).await?);
+        }
+        Ok(())
+    }
+
+    /**
+     * Return the cached snapshot of the table at the location, if there is
+     * one, without loading or refreshing it
+     */
+    pub async fn peek(&self, location: &str) -> Option<Arc<Snapshot>> {
+        let cached = self.cached(location)?;
+        let snapshot = cached.lock().await.snapshot.clone();
+        Some(snapshot)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let slots = self.slots.lock().unwrap();
+        CacheStats {
+            entries: slots.len(),
+            bytes: slots.values().map(|slot| slot.size).sum(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            updates: self.updates.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+        }
+    }
+
+    /**
+     * The DeltaTable and the snapshot each hold a copy of the file list
+     */
+    fn size_of(snapshot: &Snapshot) -> usize {
+        2 * snapshot.estimated_size()
+    }
+
+    fn insert(&self, location: &str, table: CachedTable) {
+        let size = TableCache::size_of(&table.snapshot);
+        if self.config.max_entries == 0 || size > self.config.max_bytes {
+            debug!("Not caching {} which needs about {} bytes", location, size);
+            return;
+        }
+
+        let mut slots = self.slots.lock().unwrap();
+        slots.insert(
+            location.to_string(),
+            Slot {
+                table: Arc::new(Mutex::new(table)),
+                size,
+                last_used: Instant::now(),
+            },
+        );
+        self.evict(&mut slots);
+    }
+
+    fn touch(&self, location: &str, snapshot: &Snapshot) {
+        let mut slots = self.slots.lock().unwrap();
+        if let Some(slot) = slots.get_mut(location) {
+            slot.size = TableCache::size_of(snapshot);
+            slot.last_used = Instant::now();
+        }
+        self.evict(&mut slots);
+    }
+
+    /**
+     * Account for the new size of a refreshed table, without counting it as
+     * recently used
+     */
+    fn resize(&self, location: &str, snapshot: &Snapshot) {
+        let mut slots = self.slots.lock().unwrap();
+        if let Some(slot) = slots.get_mut(location) {
+            slot.size = TableCache::size_of(snapshot);
+        }
+        self.evict(&mut slots);
+    }
+
+    /**
+     * Evict the least recently used tables until the cache is within bounds
+     */
+    fn evict(&self, slots: &mut HashMap<String, Slot>) {
+        loop {
+            let bytes: usize = slots.values().map(|slot| slot.size).sum();
+            if slots.len() <= self.config.max_entries && bytes <= self.config.max_bytes {
+                return;
+            }
+
+            let oldest = slots
+                .iter()
+                .min_by_key(|(_, slot)| slot.last_used)
+                .map(|(location, _)| location.clone());
+
+            match oldest {
+                Some(location) => {
+                    debug!("Evicting {} from the table cache", location);
+                    slots.remove(&location);
+                    count(&self.evictions, "eviction");
+                }
+                None => return,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Write a commit to the log of the Delta table at the location
+    fn commit(location: &str, version: i64, actions: &[serde_json::Value]) {
+        let lines: Vec<String> = actions.iter().map(|action| action.to_string()).collect();
+        std::fs::write(
+            format!("{}/_delta_log/{:020}.json", location, version),
+            lines.join("\n"),
+        )
+        .unwrap();
+    }
+
+    fn add(path: &str) -> serde_json::Value {
+        json!({"add": {
+            "path": path,
+            "partitionValues": {},
+            "size": 1024,
+            "modificationTime": 0,
+            "dataChange": true,
+        }})
+    }
+
+    /// A new Delta table with a single data file in a temporary directory
+    fn table() -> String {
+        table_with(
+            json!({"minReaderVersion": 1, "minWriterVersion": 2}),
+            json!({}),
+        )
+    }
+
+    /// A new Delta table with the protocol and configuration, and a single
+    /// data file, in a temporary directory
+    pub(crate) fn table_with(
+        protocol: serde_json::Value,
+        configuration: serde_json::Value,
+    ) -> String {
+        let location = std::env::temp_dir()
+            .join(format!("riverbank-{}", uuid::Uuid::new_v4()))
+            .to_string_lossy()
+            .to_string();
+        std::fs::create_dir_all(format!("{}/_delta_log", location)).unwrap();
+        commit(
+            &location,
+            0,
+            &[
+                json!({ "protocol": protocol }),
+                json!({"metaData": {
+                    "id": uuid::Uuid::new_v4().to_string(),
+                    "format": {"provider": "parquet", "options": {}},
+                    "schemaString": r#"{"type":"struct","fields":[]}"#,
+                    "partitionColumns": [],
+                    "configuration": configuration,
+                    "createdTime": 0,
+                }}),
+                add("part-00000.parquet"),
+            ],
+        );
+        location
+    }
+
+    /// A cached copy of the table whose snapshot lists the data file many times
+    async fn cached(location: &str, files: usize) -> CachedTable {
+        let mut delta = deltalake::open_table(location).await.unwrap();
+        let mut snapshot = Snapshot::from_table(&mut delta).await.unwrap();
+        snapshot.files = vec![snapshot.files[0].clone(); files];
+        CachedTable {
+            delta,
+            snapshot: Arc::new(snapshot),
+            refreshed_at: Instant::now(),
+        }
+    }
+
+    fn config(ttl_seconds: u64, max_entries: usize, max_bytes: usize) -> TableCacheConfig {
+        TableCacheConfig {
+            ttl_seconds,
+            max_entries,
+            max_bytes,
+        }
+    }
+
+    #[async_std::test]
+    async fn test_hits_and_misses() {
+        let location = table();
+        let cache = TableCache::new(config(3600, 10, usize::MAX));
+
+        assert_eq!(0, cache.load(&location).await.unwrap().version);
+        assert_eq!(0, cache.load(&location).await.unwrap().version);
+
+        let stats = cache.stats();
+        assert_eq!((1, 1, 1), (stats.entries, stats.hits, stats.misses));
+        std::fs::remove_dir_all(&location).unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_concurrent_misses() {
+        let location = table();
+        let cache = TableCache::new(config(3600, 10, usize::MAX));
+
+        let (first, second) =
+            futures_lite::future::zip(cache.load(&location), cache.load(&location)).await;
+        assert!(Arc::ptr_eq(&first.unwrap(), &second.unwrap()));
+
+        let stats = cache.stats();
+        assert_eq!((1, 1), (stats.hits, stats.misses));
+        std::fs::remove_dir_all(&location).unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_ttl() {
+        let location = table();
+        let fresh = TableCache::new(config(3600, 10, usize::MAX));
+        let stale = TableCache::new(config(0, 10, usize::MAX));
+        fresh.load(&location).await.unwrap();
+        stale.load(&location).await.unwrap();
+
+        commit(&location, 1, &[add("part-00001.parquet")]);
+
+        let snapshot = fresh.load(&location).await.unwrap();
+        assert_eq!((0, 1), (snapshot.version, snapshot.num_files));
+        assert_eq!(0, fresh.stats().updates);
+
+        let snapshot = stale.load(&location).await.unwrap();
+        assert_eq!((1, 2), (snapshot.version, snapshot.num_files));
+        assert_eq!(1, stale.stats().updates);
+        std::fs::remove_dir_all(&location).unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_evict_least_recently_used() {
+        let location = table();
+        let cache = TableCache::new(config(3600, 2, usize::MAX));
+
+        cache.insert("a", cached(&location, 1).await);
+        async_std::task::sleep(Duration::from_millis(5)).await;
+        cache.insert("b", cached(&location, 1).await);
+        async_std::task::sleep(Duration::from_millis(5)).await;
+        let snapshot = cache.peek("a").await.unwrap();
+        cache.touch("a", &snapshot);
+        async_std::task::sleep(Duration::from_millis(5)).await;
+        cache.insert("c", cached(&location, 1).await);
+
+        assert!(cache.peek("a").await.is_some());
+        assert!(cache.peek("b").await.is_none());
+        assert!(cache.peek("c").await.is_some());
+        let stats = cache.stats();
+        assert_eq!((2, 1), (stats.entries, stats.evictions));
+        std::fs::remove_dir_all(&location).unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_max_bytes() {
+        let location = table();
+        let size = TableCache::size_of(&cached(&location, 10).await.snapshot);
+        let cache = TableCache::new(config(3600, 10, 2 * size));
+
+        cache.insert("huge", cached(&location, 30).await);
+        assert!(cache.peek("huge").await.is_none());
+        assert_eq!(0, cache.stats().evictions);
+
+        cache.insert("a", cached(&location, 10).await);
+        async_std::task::sleep(Duration::from_millis(5)).await;
+        cache.insert("b", cached(&location, 10).await);
+        assert_eq!(2 * size, cache.stats().bytes);
+
+        async_std::task::sleep(Duration::from_millis(5)).await;
+        cache.insert("c", cached(&location, 10).await);
+        assert!(cache.peek("a").await.is_none());
+        let stats = cache.stats();
+        assert_eq!(
+            (2, 2 * size, 1),
+            (stats.entries, stats.bytes, stats.evictions)
+        );
+        std::fs::remove_dir_all(&location).unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_disabled() {
+        let location = table();
+        let cache = TableCache::new(config(3600, 0, usize::MAX));
+
+        cache.load(&location).await.unwrap();
+        cache.load(&location).await.unwrap();
+
+        let stats = cache.stats();
+        assert_eq!((0, 0, 2), (stats.entries, stats.hits, stats.misses));
+        std::fs::remove_dir_all(&location).unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_peek_uncached() {
+        let cache = TableCache::new(TableCacheConfig::default());
+        assert!(cache.peek("s3://delta/missing").await.is_none());
+
+        let stats = cache.stats();
+        assert_eq!((0, 0, 0), (stats.entries, stats.hits, stats.misses));
+    }
+
+    #[async_std::test]
+    async fn test_protocol_reader_features() {
+        let location = table_with(
+            json!({
+                "minReaderVersion": 3,
+                "minWriterVersion": 7,
+                "readerFeatures": ["timestampNtz"],
+                "writerFeatures": ["timestampNtz"],
+            }),
+            json!({"delta.columnMapping.mode": "name"}),
+        );
+        let mut delta = deltalake::open_table(&location).await.unwrap();
+        let snapshot = Snapshot::from_table(&mut delta).await.unwrap();
+        assert_eq!(vec!["timestampNtz".to_string()], snapshot.reader_features);
+
+        // Without reader features in the protocol they are inferred
+        commit(
+            &location,
+            1,
+            &[json!({"protocol": {"minReaderVersion": 2, "minWriterVersion": 5}})],
+        );
+        delta.update().await.unwrap();
+        let snapshot = Snapshot::from_table(&mut delta).await.unwrap();
+        assert_eq!(vec!["columnMapping".to_string()], snapshot.reader_features);
+        std::fs::remove_dir_all(&location).unwrap();
+    }
+}
@@ -0,0 +1,218 @@
+/*
+ * The capabilities module negotiates what a client can read, from the
+ * `delta-sharing-capabilities` header it sends, against the reader features
+ * which a table requires
+ */
+use deltalake::DeltaTableMetaData;
+use std::collections::HashMap;
+
+/// The request header in which clients declare their capabilities
+pub const HEADER: &str = "delta-sharing-capabilities";
+
+pub const DELETION_VECTORS: &str = "deletionVectors";
+pub const COLUMN_MAPPING: &str = "columnMapping";
+pub const TIMESTAMP_NTZ: &str = "timestampNtz";
+
+/// The features of tables which cannot be served in either response format,
+/// since the Delta library drops the deletion vectors of the add actions
+pub const UNSUPPORTED: &[&str] = &[DELETION_VECTORS];
+
+/**
+ * The shapes in which the lines of metadata and query responses are written
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResponseFormat {
+    /// The original shape, with files described by riverbank
+    Parquet,
+    /// The actions of the Delta log, with presigned URLs in place of paths
+    Delta,
+}
+
+impl ResponseFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ResponseFormat::Parquet => "parquet",
+            ResponseFormat::Delta => "delta",
+        }
+    }
+}
+
+/**
+ * The capabilities declared by a client, e.g.
+ * `responseformat=delta;readerfeatures=deletionvectors,columnmapping`
+ *
+ * Keys and values are compared case-insensitively, and keys which are not
+ * understood are ignored
+ */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Capabilities {
+    pub response_formats: Vec<String>,
+    pub reader_features: Vec<String>,
+}
+
+impl Capabilities {
+    pub fn parse(header: &str) -> Self {
+        let mut capabilities = Self::default();
+
+        for pair in header.split(';') {
+            let mut parts = pair.splitn(2, '=');
+            let key = parts.next().unwrap_or_default().trim().to_lowercase();
+            let values = parts
+                .next()
+                .unwrap_or_default()
+                .split(',')
+                .map(|value| value.trim().to_lowercase())
+                .filter(|value| !value.is_empty());
+
+            match key.as_str() {
+                "responseformat" => capabilities.response_formats.extend(values),
+                "readerfeatures" => capabilities.reader_features.extend(values),
+                _ => {}
+            }
+        }
+        capabilities
+    }
+
+    /**
+     * The first of the response formats declared by the client which is
+     * supported, in the order of the client's preference
+     */
+    pub fn response_format(&self) -> ResponseFormat {
+        self.response_formats
+            .iter()
+            .find_map(|format| match format.as_str() {
+                "parquet" => Some(ResponseFormat::Parquet),
+                "delta" => Some(ResponseFormat::Delta),
+                _ => None,
+            })
+            .unwrap_or(ResponseFormat::Parquet)
+    }
+
+    /// The required features which the client did not declare
+    pub fn missing<'a>(&self, required: &'a [String]) -> Vec<&'a str> {
+        required
+            .iter()
+            .filter(|feature| !self.reader_features.contains(&feature.to_lowercase()))
+            .map(String::as_str)
+            .collect()
+    }
+}
+
+/**
+ * The reader features a table requires, inferred from its metadata for tables
+ * whose protocol action does not list them
+ */
+pub fn required_reader_features(metadata: &DeltaTableMetaData) -> Vec<String> {
+    // The type can be nested anywhere in the schema, so it is simplest to
+    // look for it in the serialized form
+    let schema = serde_json::to_string(&metadata.schema).unwrap_or_default();
+    features_for(&metadata.configuration, &schema)
+}
+
+/**
+ * The reader features required by the configuration and the serialized
+ * schema of a table
+ */
+fn features_for(configuration: &HashMap<String, Option<String>>, schema: &str) -> Vec<String> {
+    let setting = |key: &str| {
+        configuration
+            .get(key)
+            .cloned()
+            .flatten()
+            .map(|value| value.to_lowercase())
+    };
+    let mut features = vec![];
+
+    if setting("delta.enableDeletionVectors").as_deref() == Some("true") {
+        features.push(DELETION_VECTORS.to_string());
+    }
+    if matches!(
+        setting("delta.columnMapping.mode").as_deref(),
+        Some("name") | Some("id")
+    ) {
+        features.push(COLUMN_MAPPING.to_string());
+    }
+    if schema.contains("\"timestamp_ntz\"") {
+        features.push(TIMESTAMP_NTZ.to_string());
+    }
+    features
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let capabilities = Capabilities::parse(
+            "responseformat=delta ; readerfeatures=DeletionVectors,columnmapping",
+        );
+        assert_eq!(vec!["delta"], capabilities.response_formats);
+        assert_eq!(
+            vec!["deletionvectors", "columnmapping"],
+            capabilities.reader_features
+        );
+        assert_eq!(Capabilities::default(), Capabilities::parse("unknown=1;;"));
+    }
+
+    #[test]
+    fn test_response_format() {
+        assert_eq!(
+            ResponseFormat::Parquet,
+            Capabilities::default().response_format()
+        );
+        assert_eq!(
+            ResponseFormat::Delta,
+            Capabilities::parse("responseformat=unknown,delta,parquet").response_format()
+        );
+    }
+
+    #[test]
+    fn test_missing() {
+        let capabilities = Capabilities::parse("readerfeatures=deletionvectors");
+        let required = vec![DELETION_VECTORS.to_string(), COLUMN_MAPPING.to_string()];
+        assert_eq!(vec![COLUMN_MAPPING], capabilities.missing(&required));
+    }
+
+    #[test]
+    fn test_features_for() {
+        let configuration = |settings: &[(&str, &str)]| -> HashMap<String, Option<String>> {
+            settings
+                .iter()
+                .map(|(key, value)| (key.to_string(), Some(value.to_string())))
+                .collect()
+        };
+        let schema = r#"{"type":"struct","fields":[{"name":"id","type":"long","nullable":false,"metadata":{}}]}"#;
+
+        assert!(features_for(&HashMap::new(), schema).is_empty());
+        assert_eq!(
+            vec![DELETION_VECTORS],
+            features_for(
+                &configuration(&[("delta.enableDeletionVectors", "TRUE")]),
+                schema
+            )
+        );
+        assert!(features_for(
+            &configuration(&[("delta.enableDeletionVectors", "false")]),
+            schema
+        )
+        .is_empty());
+        for mode in &["name", "id"] {
+            assert_eq!(
+                vec![COLUMN_MAPPING],
+                features_for(
+                    &configuration(&[("delta.columnMapping.mode", mode)]),
+                    schema
+                )
+            );
+        }
+        assert!(features_for(
+            &configuration(&[("delta.columnMapping.mode", "none")]),
+            schema
+        )
+        .is_empty());
+
+        let nested = r#"{"type":"struct","fields":[{"name":"events","type":{"type":"array","elementType":{"type":"struct","fields":[{"name":"at","type":"timestamp_ntz","nullable":true,"metadata":{}}]},"containsNull":true},"nullable":true,"metadata":{}}]}"#;
+        assert_eq!(vec![TIMESTAMP_NTZ], features_for(&HashMap::new(), nested));
+    }
+}
@@ -0,0 +1,300 @@
+/*
+ * The file catalog serves the shares and recipients written down in the
+ * configuration file, so riverbank can run without any database.
+ *
+ * Recipients are configured with the SHA-256 digest of their bearer token
+ * rather than the token itself, and nothing can be created or revoked
+ */
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use super::memory::{Catalog, MemoryCatalog};
+use super::{parse_table_ref, CatalogStore, Result};
+use crate::config::{RecipientConfig, ShareConfig};
+use crate::models::{Actor, AdminAudit, PrimitiveTable, Schema, Share, Table, Token};
+use crate::state::Tokened;
+
+#[derive(Debug)]
+pub struct FileCatalog {
+    inner: MemoryCatalog,
+}
+
+/**
+ * Ids are derived from the names, so that they stay the same across restarts
+ */
+fn id_for(kind: &str, name: &str) -> Uuid {
+    Uuid::new_v5(
+        &Uuid::NAMESPACE_URL,
+        format!("riverbank:{}:{}", kind, name).as_bytes(),
+    )
+}
+
+/**
+ * The hex encoded SHA-256 digest of a bearer token, as it is configured
+ */
+pub fn token_digest(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+fn invalid(message: String) -> sqlx::Error {
+    sqlx::Error::Configuration(message.into())
+}
+
+fn read_only() -> sqlx::Error {
+    invalid("The catalog is read from the configuration file and cannot be changed".to_string())
+}
+
+impl FileCatalog {
+    pub fn new(shares: &[ShareConfig], recipients: &[RecipientConfig]) -> Result<Self> {
+        let now = Utc::now();
+        let mut catalog = Catalog::default();
+
+        for share in shares {
+            let share_id = id_for("share", &share.name);
+            catalog.shares.push(Share {
+                id: share_id,
+                name: share.name.clone(),
+                created_at: now,
+            });
+
+            for schema in share.schemas.iter() {
+                let path = format!("{}.{}", share.name, schema.name);
+                let schema_id = id_for("schema", &path);
+                catalog.schemas.push(Schema {
+                    id: schema_id,
+                    name: schema.name.clone(),
+                    share_id,
+                    share_name: share.name.clone(),
+                    created_at: now,
+                });
+
+                for table in schema.tables.iter() {
+                    catalog.tables.push(PrimitiveTable {
+                        id: id_for("table", &format!("{}.{}", path, table.name)),
+                        name: table.name.clone(),
+                        location: table.location.clone(),
+                        schema_id,
+                        created_at: now,
+                        description: table.description.clone(),
+                    });
+                }
+            }
+        }
+
+        for recipient in recipients {
+            let digest = recipient.token_sha256.to_lowercase();
+            let token_id = id_for("token", &format!("{}:{}", recipient.name, digest));
+
+            for reference in recipient.tables.iter() {
+                let (share, schema, table) = parse_table_ref(reference).map_err(invalid)?;
+                let table_id = id_for("table", &format!("{}.{}.{}", share, schema, table));
+                if !catalog.tables.iter().any(|t| t.id == table_id) {
+                    return Err(invalid(format!(
+                        "The recipient {} is granted {}, which is not a configured table",
+                        recipient.name, reference
+                    )));
+                }
+                catalog.grants.push((token_id, table_id));
+            }
+
+            catalog.tokens.push(Token {
+                id: token_id,
+                name: recipient.name.clone(),
+                token: digest,
+                expires_at: recipient.expires_at.unwrap_or(chrono::MAX_DATETIME),
+                created_at: now,
+            });
+        }
+
+        Ok(Self {
+            inner: catalog.into(),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl CatalogStore for FileCatalog {
+    async fn list_shares(&self) -> Result<Vec<Share>> {
+        self.inner.list_shares().await
+    }
+
+    async fn shares_for_token(&self, token_id: &Uuid) -> Result<Vec<Share>> {
+        self.inner.shares_for_token(token_id).await
+    }
+
+    async fn find_share(&self, share: &str, token_id: &Uuid) -> Result<Share> {
+        self.inner.find_share(share, token_id).await
+    }
+
+    async fn create_share(&self, _actor: &Actor, _name: &str) -> Result<Share> {
+        Err(read_only())
+    }
+
+    async fn list_schemas(&self) -> Result<Vec<Schema>> {
+        self.inner.list_schemas().await
+    }
+
+    async fn schemas_for_token(&self, share: &str, token_id: &Uuid) -> Result<Vec<Schema>> {
+        self.inner.schemas_for_token(share, token_id).await
+    }
+
+    async fn find_schema(&self, share: &str, schema: &str) -> Result<Schema> {
+        self.inner.find_schema(share, schema).await
+    }
+
+    async fn create_schema(&self, _actor: &Actor, _name: &str, _share_id: &Uuid) -> Result<Schema> {
+        Err(read_only())
+    }
+
+    async fn list_tables(&self) -> Result<Vec<Table>> {
+        self.inner.list_tables().await
+    }
+
+    async fn tables_for_token(
+        &self,
+        share: &str,
+        schema: &str,
+        token_id: &Uuid,
+    ) -> Result<Vec<Table>> {
+        self.inner.tables_for_token(share, schema, token_id).await
+    }
+
+    async fn all_tables_for_token(&self, share: &str, token_id: &Uuid) -> Result<Vec<Table>> {
+        self.inner.all_tables_for_token(share, token_id).await
+    }
+
+    async fn find_table(
+        &self,
+        share: &str,
+        schema: &str,
+        table: &str,
+        token_id: &Uuid,
+    ) -> Result<Table> {
+        self.inner.find_table(share, schema, table, token_id).await
+    }
+
+    async fn create_table(
+        &self,
+        _actor: &Actor,
+        _name: &str,
+        _location: &str,
+        _schema_id: &Uuid,
+    ) -> Result<Table> {
+        Err(read_only())
+    }
+
+    async fn describe_table(
+        &self,
+        _actor: &Actor,
+        _id: &Uuid,
+        _description: Option<&str>,
+    ) -> Result<Table> {
+        Err(read_only())
+    }
+
+    async fn list_tokens(&self) -> Result<Vec<Token>> {
+        self.inner.list_tokens().await
+    }
+
+    async fn token(&self, id: &Uuid) -> Result<Token> {
+        self.inner.token(id).await
+    }
+
+    async fn authenticate(&self, secret: &str) -> Result<Option<Tokened>> {
+        self.inner.authenticate(&token_digest(secret)).await
+    }
+
+    async fn token_for_recipient(&self, recipient: &str) -> Result<Option<Tokened>> {
+        self.inner.token_for_recipient(recipient).await
+    }
+
+    async fn create_token(&self, _actor: &Actor, _name: &str, _tables: &[Uuid]) -> Result<Token> {
+        Err(read_only())
+    }
+
+    async fn revoke_token(&self, _actor: &Actor, _id: &Uuid) -> Result<Token> {
+        Err(read_only())
+    }
+
+    /// Nothing is ever changed through the file catalog
+    async fn audit_trail(
+        &self,
+        _object_type: Option<&str>,
+        _object_id: Option<&Uuid>,
+    ) -> Result<Vec<AdminAudit>> {
+        Ok(vec![])
+    }
+
+    async fn migrate(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{SchemaConfig, TableConfig};
+
+    fn shares() -> Vec<ShareConfig> {
+        vec![ShareConfig {
+            name: "vaccine_share".to_string(),
+            schemas: vec![SchemaConfig {
+                name: "acme_vaccine_data".to_string(),
+                tables: vec![
+                    TableConfig {
+                        name: "vaccine_ingredients".to_string(),
+                        location: "s3://delta/ingredients".to_string(),
+                        description: None,
+                    },
+                    TableConfig {
+                        name: "vaccine_patients".to_string(),
+                        location: "s3://delta/patients".to_string(),
+                        description: None,
+                    },
+                ],
+            }],
+        }]
+    }
+
+    fn recipient(tables: &[&str]) -> RecipientConfig {
+        RecipientConfig {
+            name: "acme".to_string(),
+            token_sha256: token_digest("secret"),
+            expires_at: None,
+            tables: tables.iter().map(|t| t.to_string()).collect(),
+        }
+    }
+
+    #[async_std::test]
+    async fn test_hashed_tokens() {
+        let catalog = FileCatalog::new(
+            &shares(),
+            &[recipient(&[
+                "vaccine_share.acme_vaccine_data.vaccine_ingredients",
+            ])],
+        )
+        .unwrap();
+
+        assert!(catalog.authenticate("wrong").await.unwrap().is_none());
+        let tokened = catalog.authenticate("secret").await.unwrap().unwrap();
+        let tables = catalog
+            .tables_for_token("vaccine_share", "acme_vaccine_data", &tokened.id)
+            .await
+            .unwrap();
+        assert_eq!(
+            vec!["vaccine_ingredients"],
+            tables.iter().map(Table::name).collect::<Vec<_>>()
+        );
+        let actor = Actor {
+            name: "admin".to_string(),
+            source_ip: None,
+        };
+        assert!(catalog.create_share(&actor, "other").await.is_err());
+    }
+
+    #[test]
+    fn test_unknown_grant() {
+        assert!(FileCatalog::new(&shares(), &[recipient(&["vaccine_share.nope.nope"])]).is_err());
+    }
+}
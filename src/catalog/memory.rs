@@ -0,0 +1,612 @@
+/*
+ * The memory catalog keeps everything in the process, which is mostly useful
+ * for tests since it starts out empty on every start
+ */
+use chrono::{Duration, Utc};
+use serde_json::json;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+use super::{CatalogStore, Result};
+use crate::models::{Actor, AdminAudit, AuditEntry, PrimitiveTable, Schema, Share, Table, Token};
+use crate::state::Tokened;
+
+#[derive(Debug, Default)]
+pub(super) struct Catalog {
+    pub(super) shares: Vec<Share>,
+    pub(super) schemas: Vec<Schema>,
+    pub(super) tables: Vec<PrimitiveTable>,
+    pub(super) tokens: Vec<Token>,
+    /// Pairs of token and table ids
+    pub(super) grants: Vec<(Uuid, Uuid)>,
+    /// The audit trail, oldest first
+    pub(super) audit: Vec<AdminAudit>,
+}
+
+impl Catalog {
+    fn schema(&self, id: &Uuid) -> Result<&Schema> {
+        self.schemas
+            .iter()
+            .find(|schema| schema.id == *id)
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    fn table(&self, inner: &PrimitiveTable) -> Result<Table> {
+        Ok(Table::new(
+            inner.clone(),
+            self.schema(&inner.schema_id)?.clone(),
+        ))
+    }
+
+    fn granted(&self, token_id: &Uuid) -> Vec<&PrimitiveTable> {
+        self.tables
+            .iter()
+            .filter(|table| self.grants.contains(&(*token_id, table.id)))
+            .collect()
+    }
+
+    /// Append the entry to the audit trail, under the same lock as the change
+    fn record(&mut self, entry: AuditEntry) {
+        self.audit.push(AdminAudit {
+            id: Uuid::new_v4(),
+            actor: entry.actor,
+            action: entry.action.to_string(),
+            object_type: entry.object_type.to_string(),
+            object_id: entry.object_id,
+            old_value: entry.old_value,
+            new_value: entry.new_value,
+            source_ip: entry.source_ip,
+            created_at: Utc::now(),
+        });
+    }
+
+    fn active_tokens(&self) -> impl Iterator<Item = &Token> {
+        let now = Utc::now();
+        self.tokens
+            .iter()
+            .filter(move |token| token.expires_at > now)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryCatalog {
+    catalog: Mutex<Catalog>,
+}
+
+impl From<Catalog> for MemoryCatalog {
+    fn from(catalog: Catalog) -> Self {
+        Self {
+            catalog: Mutex::new(catalog),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl CatalogStore for MemoryCatalog {
+    async fn list_shares(&self) -> Result<Vec<Share>> {
+        Ok(self.catalog.lock().unwrap().shares.clone())
+    }
+
+    async fn shares_for_token(&self, token_id: &Uuid) -> Result<Vec<Share>> {
+        let catalog = self.catalog.lock().unwrap();
+        let granted = catalog.granted(token_id);
+        let mut shares: Vec<Share> = catalog
+            .shares
+            .iter()
+            .filter(|share| {
+                granted.iter().any(|table| {
+                    catalog
+                        .schema(&table.schema_id)
+                        .map_or(false, |schema| schema.share_id == share.id)
+                })
+            })
+            .cloned()
+            .collect();
+        shares.sort_by(|a, b| (&a.name, a.id).cmp(&(&b.name, b.id)));
+        Ok(shares)
+    }
+
+    async fn find_share(&self, share: &str, token_id: &Uuid) -> Result<Share> {
+        self.shares_for_token(token_id)
+            .await?
+            .into_iter()
+            .find(|found| found.name == share)
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    async fn create_share(&self, actor: &Actor, name: &str) -> Result<Share> {
+        let share = Share {
+            id: Uuid::new_v4(),
+            name: name.to_string(),
+            created_at: Utc::now(),
+        };
+        let mut catalog = self.catalog.lock().unwrap();
+        catalog.shares.push(share.clone());
+        catalog.record(actor.entry(
+            "create_share",
+            "share",
+            &share.id,
+            None,
+            Some(json!({ "name" : name })),
+        ));
+        Ok(share)
+    }
+
+    async fn list_schemas(&self) -> Result<Vec<Schema>> {
+        Ok(self.catalog.lock().unwrap().schemas.clone())
+    }
+
+    async fn schemas_for_token(&self, share: &str, token_id: &Uuid) -> Result<Vec<Schema>> {
+        let catalog = self.catalog.lock().unwrap();
+        let granted = catalog.granted(token_id);
+        let mut schemas: Vec<Schema> = catalog
+            .schemas
+            .iter()
+            .filter(|schema| schema.share_name == share)
+            .filter(|schema| granted.iter().any(|table| table.schema_id == schema.id))
+            .cloned()
+            .collect();
+        schemas.sort_by(|a, b| (&a.name, a.id).cmp(&(&b.name, b.id)));
+        Ok(schemas)
+    }
+
+    async fn find_schema(&self, share: &str, schema: &str) -> Result<Schema> {
+        self.catalog
+            .lock()
+            .unwrap()
+            .schemas
+            .iter()
+            .find(|found| found.share_name == share && found.name == schema)
+            .cloned()
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    async fn create_schema(&self, actor: &Actor, name: &str, share_id: &Uuid) -> Result<Schema> {
+        let mut catalog = self.catalog.lock().unwrap();
+        let share = catalog
+            .shares
+            .iter()
+            .find(|share| share.id == *share_id)
+            .ok_or(sqlx::Error::RowNotFound)?;
+        let schema = Schema {
+            id: Uuid::new_v4(),
+            name: name.to_string(),
+            share_id: share.id,
+            share_name: share.name.clone(),
+            created_at: Utc::now(),
+        };
+        catalog.schemas.push(schema.clone());
+        catalog.record(actor.entry(
+            "create_schema",
+            "schema",
+            &schema.id,
+            None,
+            Some(json!({
+                "name" : name,
+                "share_id" : share_id,
+            })),
+        ));
+        Ok(schema)
+    }
+
+    async fn list_tables(&self) -> Result<Vec<Table>> {
+        let catalog = self.catalog.lock().unwrap();
+        catalog
+            .tables
+            .iter()
+            .map(|inner| catalog.table(inner))
+            .collect()
+    }
+
+    async fn tables_for_token(
+        &self,
+        share: &str,
+        schema: &str,
+        token_id: &Uuid,
+    ) -> Result<Vec<Table>> {
+        let schema = self.find_schema(share, schema).await?;
+        let catalog = self.catalog.lock().unwrap();
+        let mut tables: Vec<Table> = catalog
+            .granted(token_id)
+            .into_iter()
+            .filter(|inner| inner.schema_id == schema.id)
+            .map(|inner| Table::new(inner.clone(), schema.clone()))
+            .collect();
+        tables.sort_by(|a, b| (a.name(), a.id()).cmp(&(b.name(), b.id())));
+        Ok(tables)
+    }
+
+    async fn all_tables_for_token(&self, share: &str, token_id: &Uuid) -> Result<Vec<Table>> {
+        let share = self.find_share(share, token_id).await?;
+        let catalog = self.catalog.lock().unwrap();
+        let mut tables = catalog
+            .granted(token_id)
+            .into_iter()
+            .map(|inner| catalog.table(inner))
+            .filter(|table| table.as_ref().map_or(true, |t| t.share() == share.name))
+            .collect::<Result<Vec<_>>>()?;
+        tables.sort_by(|a, b| (a.schema(), a.name(), a.id()).cmp(&(b.schema(), b.name(), b.id())));
+        Ok(tables)
+    }
+
+    async fn find_table(
+        &self,
+        share: &str,
+        schema: &str,
+        table: &str,
+        token_id: &Uuid,
+    ) -> Result<Table> {
+        self.tables_for_token(share, schema, token_id)
+            .await?
+            .into_iter()
+            .find(|found| found.name() == table)
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    async fn create_table(
+        &self,
+        actor: &Actor,
+        name: &str,
+        location: &str,
+        schema_id: &Uuid,
+    ) -> Result<Table> {
+        let mut catalog = self.catalog.lock().unwrap();
+        let schema = catalog.schema(schema_id)?.clone();
+        let inner = PrimitiveTable {
+            id: Uuid::new_v4(),
+            name: name.to_string(),
+            location: location.to_string(),
+            schema_id: schema.id,
+            created_at: Utc::now(),
+            description: None,
+        };
+        catalog.tables.push(inner.clone());
+        catalog.record(actor.entry(
+            "create_table",
+            "table",
+            &inner.id,
+            None,
+            Some(json!({
+                "name" : name,
+                "location" : location,
+                "schema_id" : schema_id,
+            })),
+        ));
+        Ok(Table::new(inner, schema))
+    }
+
+    async fn describe_table(
+        &self,
+        actor: &Actor,
+        id: &Uuid,
+        description: Option<&str>,
+    ) -> Result<Table> {
+        let mut catalog = self.catalog.lock().unwrap();
+        let inner = catalog
+            .tables
+            .iter_mut()
+            .find(|table| table.id == *id)
+            .ok_or(sqlx::Error::RowNotFound)?;
+        let old = std::mem::replace(&mut inner.description, description.map(str::to_string));
+        let inner = inner.clone();
+        let table = catalog.table(&inner)?;
+        catalog.record(actor.entry(
+            "describe_table",
+            "table",
+            id,
+            Some(json!({ "description" : old })),
+            Some(json!({ "description" : description })),
+        ));
+        Ok(table)
+    }
+
+    async fn list_tokens(&self) -> Result<Vec<Token>> {
+        Ok(self
+            .catalog
+            .lock()
+            .unwrap()
+            .active_tokens()
+            .cloned()
+            .collect())
+    }
+
+    async fn token(&self, id: &Uuid) -> Result<Token> {
+        self.catalog
+            .lock()
+            .unwrap()
+            .tokens
+            .iter()
+            .find(|token| token.id == *id)
+            .cloned()
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    async fn authenticate(&self, secret: &str) -> Result<Option<Tokened>> {
+        Ok(self
+            .catalog
+            .lock()
+            .unwrap()
+            .active_tokens()
+            .find(|token| token.token == secret)
+            .cloned()
+            .map(Tokened::from))
+    }
+
+    async fn token_for_recipient(&self, recipient: &str) -> Result<Option<Tokened>> {
+        Ok(self
+            .catalog
+            .lock()
+            .unwrap()
+            .active_tokens()
+            .filter(|token| token.name == recipient)
+            .max_by_key(|token| token.created_at)
+            .cloned()
+            .map(Tokened::from))
+    }
+
+    async fn create_token(&self, actor: &Actor, name: &str, tables: &[Uuid]) -> Result<Token> {
+        let mut catalog = self.catalog.lock().unwrap();
+        if !tables
+            .iter()
+            .all(|id| catalog.tables.iter().any(|table| table.id == *id))
+        {
+            return Err(sqlx::Error::RowNotFound);
+        }
+
+        let now = Utc::now();
+        let token = Token {
+            id: Uuid::new_v4(),
+            name: name.to_string(),
+            token: Uuid::new_v4().to_hyphenated().to_string(),
+            expires_at: now + Duration::days(30),
+            created_at: now,
+        };
+        catalog.tokens.push(token.clone());
+        catalog
+            .grants
+            .extend(tables.iter().map(|table| (token.id, *table)));
+        // The secret itself must never end up in the audit trail
+        catalog.record(actor.entry(
+            "create_token",
+            "token",
+            &token.id,
+            None,
+            Some(json!({
+                "name" : name,
+                "tables" : tables,
+            })),
+        ));
+        Ok(token)
+    }
+
+    async fn revoke_token(&self, actor: &Actor, id: &Uuid) -> Result<Token> {
+        let mut catalog = self.catalog.lock().unwrap();
+        let now = Utc::now();
+        let token = catalog
+            .tokens
+            .iter_mut()
+            .find(|token| token.id == *id && token.expires_at > now)
+            .ok_or(sqlx::Error::RowNotFound)?;
+        let old = std::mem::replace(&mut token.expires_at, now);
+        let token = token.clone();
+        catalog.record(actor.entry(
+            "revoke_token",
+            "token",
+            id,
+            Some(json!({ "expires_at" : old })),
+            Some(json!({ "expires_at" : token.expires_at })),
+        ));
+        Ok(token)
+    }
+
+    async fn audit_trail(
+        &self,
+        object_type: Option<&str>,
+        object_id: Option<&Uuid>,
+    ) -> Result<Vec<AdminAudit>> {
+        Ok(self
+            .catalog
+            .lock()
+            .unwrap()
+            .audit
+            .iter()
+            .rev()
+            .filter(|entry| object_type.map_or(true, |t| entry.object_type == t))
+            .filter(|entry| object_id.map_or(true, |id| entry.object_id.as_ref() == Some(id)))
+            .take(500)
+            .cloned()
+            .collect())
+    }
+
+    async fn migrate(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn actor() -> Actor {
+        Actor {
+            name: "admin".to_string(),
+            source_ip: None,
+        }
+    }
+
+    /**
+     * A catalog with two tables in the same schema, of which the returned
+     * token is only granted the first
+     */
+    async fn catalog() -> (MemoryCatalog, Token) {
+        let catalog = MemoryCatalog::default();
+        let share = catalog
+            .create_share(&actor(), "vaccine_share")
+            .await
+            .unwrap();
+        let schema = catalog
+            .create_schema(&actor(), "acme_vaccine_data", &share.id)
+            .await
+            .unwrap();
+        let granted = catalog
+            .create_table(
+                &actor(),
+                "vaccine_ingredients",
+                "s3://delta/ingredients",
+                &schema.id,
+            )
+            .await
+            .unwrap();
+        catalog
+            .create_table(
+                &actor(),
+                "vaccine_patients",
+                "s3://delta/patients",
+                &schema.id,
+            )
+            .await
+            .unwrap();
+        let token = catalog
+            .create_token(&actor(), "acme", &[granted.inner.id])
+            .await
+            .unwrap();
+        (catalog, token)
+    }
+
+    #[async_std::test]
+    async fn test_grants() {
+        let (catalog, token) = catalog().await;
+
+        assert_eq!(1, catalog.shares_for_token(&token.id).await.unwrap().len());
+        assert_eq!(
+            1,
+            catalog
+                .schemas_for_token("vaccine_share", &token.id)
+                .await
+                .unwrap()
+                .len()
+        );
+        let tables = catalog
+            .tables_for_token("vaccine_share", "acme_vaccine_data", &token.id)
+            .await
+            .unwrap();
+        assert_eq!(
+            vec!["vaccine_ingredients"],
+            tables.iter().map(Table::name).collect::<Vec<_>>()
+        );
+
+        assert!(catalog
+            .find_table(
+                "vaccine_share",
+                "acme_vaccine_data",
+                "vaccine_patients",
+                &token.id
+            )
+            .await
+            .is_err());
+        assert!(catalog
+            .shares_for_token(&Uuid::new_v4())
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_all_tables() {
+        let (catalog, token) = catalog().await;
+
+        let share = catalog
+            .find_share("vaccine_share", &token.id)
+            .await
+            .unwrap();
+        assert_eq!("vaccine_share", share.name);
+        assert!(catalog
+            .find_share("vaccine_share", &Uuid::new_v4())
+            .await
+            .is_err());
+
+        let tables = catalog
+            .all_tables_for_token("vaccine_share", &token.id)
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![("acme_vaccine_data", "vaccine_ingredients")],
+            tables
+                .iter()
+                .map(|t| (t.schema(), t.name()))
+                .collect::<Vec<_>>()
+        );
+        assert!(catalog
+            .all_tables_for_token("other_share", &token.id)
+            .await
+            .is_err());
+    }
+
+    #[async_std::test]
+    async fn test_describe_table() {
+        let (catalog, token) = catalog().await;
+        let table = catalog
+            .find_table(
+                "vaccine_share",
+                "acme_vaccine_data",
+                "vaccine_ingredients",
+                &token.id,
+            )
+            .await
+            .unwrap();
+
+        let described = catalog
+            .describe_table(&actor(), table.id(), Some("Ingredients"))
+            .await
+            .unwrap();
+        assert_eq!(Some("Ingredients".to_string()), described.inner.description);
+        let cleared = catalog
+            .describe_table(&actor(), table.id(), None)
+            .await
+            .unwrap();
+        assert_eq!(None, cleared.inner.description);
+        assert!(catalog
+            .describe_table(&actor(), &Uuid::new_v4(), None)
+            .await
+            .is_err());
+
+        let history = catalog
+            .audit_trail(Some("table"), Some(table.id()))
+            .await
+            .unwrap();
+        assert_eq!(
+            vec!["describe_table", "describe_table", "create_table"],
+            history
+                .iter()
+                .map(|e| e.action.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            Some(json!({ "description" : "Ingredients" })),
+            history[0].old_value
+        );
+    }
+
+    #[async_std::test]
+    async fn test_revoke() {
+        let (catalog, token) = catalog().await;
+
+        let found = catalog.authenticate(&token.token).await.unwrap();
+        assert_eq!(Some(token.id), found.map(|t| t.id));
+
+        catalog.revoke_token(&actor(), &token.id).await.unwrap();
+        assert!(catalog.authenticate(&token.token).await.unwrap().is_none());
+        assert!(catalog.list_tokens().await.unwrap().is_empty());
+        assert!(catalog.revoke_token(&actor(), &token.id).await.is_err());
+        assert_eq!(
+            1,
+            catalog
+                .audit_trail(Some("token"), Some(&token.id))
+                .await
+                .unwrap()
+                .iter()
+                .filter(|e| e.action == "revoke_token")
+                .count()
+        );
+    }
+}
@@ -0,0 +1,153 @@
+/*
+ * The catalog module stores what is shared with whom: shares, schemas,
+ * tables, the tokens of recipients and the tables granted to each token.
+ *
+ * The CatalogStore trait is implemented for Postgres, SQLite, memory and the
+ * configuration file, and the backend is selected in the configuration.
+ *
+ * Every change is recorded in the audit trail of the catalog along with the
+ * actor who made it, in the same transaction as the change itself
+ */
+use async_std::sync::Arc;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::config::{CatalogConfig, Config};
+use crate::models::{Actor, AdminAudit, Schema, Share, Table, Token};
+use crate::state::Tokened;
+
+pub mod file;
+pub mod memory;
+pub mod postgres;
+pub mod sqlite;
+
+/**
+ * Every backend reports errors as sqlx errors, so that a missing record is a
+ * RowNotFound no matter where the catalog is kept
+ */
+pub type Result<T> = std::result::Result<T, sqlx::Error>;
+
+#[async_trait::async_trait]
+pub trait CatalogStore: std::fmt::Debug + Send + Sync {
+    async fn list_shares(&self) -> Result<Vec<Share>>;
+    /// The shares with at least one table granted to the token
+    async fn shares_for_token(&self, token_id: &Uuid) -> Result<Vec<Share>>;
+    /// The share, if at least one of its tables is granted to the token
+    async fn find_share(&self, share: &str, token_id: &Uuid) -> Result<Share>;
+    async fn create_share(&self, actor: &Actor, name: &str) -> Result<Share>;
+
+    async fn list_schemas(&self) -> Result<Vec<Schema>>;
+    /// The schemas of the share with at least one table granted to the token
+    async fn schemas_for_token(&self, share: &str, token_id: &Uuid) -> Result<Vec<Schema>>;
+    async fn find_schema(&self, share: &str, schema: &str) -> Result<Schema>;
+    async fn create_schema(&self, actor: &Actor, name: &str, share_id: &Uuid) -> Result<Schema>;
+
+    async fn list_tables(&self) -> Result<Vec<Table>>;
+    /// The tables of the schema which are granted to the token
+    async fn tables_for_token(
+        &self,
+        share: &str,
+        schema: &str,
+        token_id: &Uuid,
+    ) -> Result<Vec<Table>>;
+    /// The tables in every schema of the share which are granted to the token
+    async fn all_tables_for_token(&self, share: &str, token_id: &Uuid) -> Result<Vec<Table>>;
+    /// The table, if it is granted to the token
+    async fn find_table(
+        &self,
+        share: &str,
+        schema: &str,
+        table: &str,
+        token_id: &Uuid,
+    ) -> Result<Table>;
+    async fn create_table(
+        &self,
+        actor: &Actor,
+        name: &str,
+        location: &str,
+        schema_id: &Uuid,
+    ) -> Result<Table>;
+    /// Override the description of the Delta table, or clear it with None
+    async fn describe_table(
+        &self,
+        actor: &Actor,
+        id: &Uuid,
+        description: Option<&str>,
+    ) -> Result<Table>;
+
+    /// The tokens which have not expired yet
+    async fn list_tokens(&self) -> Result<Vec<Token>>;
+    async fn token(&self, id: &Uuid) -> Result<Token>;
+    /// The unexpired token with the given secret
+    async fn authenticate(&self, secret: &str) -> Result<Option<Tokened>>;
+    /// The most recent unexpired token named after the recipient
+    async fn token_for_recipient(&self, recipient: &str) -> Result<Option<Tokened>>;
+    /// Create a token with a new secret, granting it the tables
+    async fn create_token(&self, actor: &Actor, name: &str, tables: &[Uuid]) -> Result<Token>;
+    /// Expire the token right away, failing with RowNotFound for a token
+    /// which is not active
+    async fn revoke_token(&self, actor: &Actor, id: &Uuid) -> Result<Token>;
+
+    /// The history of changes, newest first, optionally only for the given
+    /// type of object or a single object
+    async fn audit_trail(
+        &self,
+        object_type: Option<&str>,
+        object_id: Option<&Uuid>,
+    ) -> Result<Vec<AdminAudit>>;
+    /// Apply the pending migrations of the backend
+    async fn migrate(&self) -> Result<()>;
+}
+
+/**
+ * Open the configured catalog, the Postgres one sharing the pool used for
+ * everything else
+ */
+pub async fn connect(config: &Config, db: Option<&PgPool>) -> Result<Arc<dyn CatalogStore>> {
+    Ok(match &config.catalog {
+        CatalogConfig::Postgres => match db {
+            Some(db) => Arc::new(postgres::PostgresCatalog::new(db.clone())),
+            None => {
+                return Err(sqlx::Error::Configuration(
+                    "The postgres catalog needs DATABASE_URL to be set".into(),
+                ))
+            }
+        },
+        CatalogConfig::Sqlite { url } => Arc::new(sqlite::SqliteCatalog::connect(url).await?),
+        CatalogConfig::Memory => Arc::new(memory::MemoryCatalog::default()),
+        CatalogConfig::File => {
+            Arc::new(file::FileCatalog::new(&config.shares, &config.recipients)?)
+        }
+    })
+}
+
+/**
+ * Split a `share.schema.table` reference into its parts
+ */
+pub fn parse_table_ref(reference: &str) -> std::result::Result<(&str, &str, &str), String> {
+    let parts: Vec<&str> = reference.splitn(3, '.').collect();
+    match parts.as_slice() {
+        [share, schema, table] if !share.is_empty() && !schema.is_empty() && !table.is_empty() => {
+            Ok((*share, *schema, *table))
+        }
+        _ => Err(format!(
+            "`{}` is not a table reference of the form share.schema.table",
+            reference
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_table_ref() {
+        assert_eq!(
+            Ok(("vaccine_share", "acme_vaccine_data", "vaccine_ingredients")),
+            parse_table_ref("vaccine_share.acme_vaccine_data.vaccine_ingredients")
+        );
+        assert!(parse_table_ref("vaccine_share.vaccine_ingredients").is_err());
+        assert!(parse_table_ref("vaccine_share..vaccine_ingredients").is_err());
+    }
+}
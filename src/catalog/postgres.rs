@@ -0,0 +1,218 @@
+/*
+ * The Postgres catalog is backed by the models, in the same database as the
+ * access log, audit trail and the other records of riverbank
+ */
+use serde_json::json;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use super::{CatalogStore, Result};
+use crate::models::{Actor, AdminAudit, Schema, Share, Table, Token, MIGRATOR};
+use crate::state::Tokened;
+
+#[derive(Clone, Debug)]
+pub struct PostgresCatalog {
+    db: PgPool,
+}
+
+impl PostgresCatalog {
+    pub fn new(db: PgPool) -> Self {
+        Self { db }
+    }
+}
+
+#[async_trait::async_trait]
+impl CatalogStore for PostgresCatalog {
+    async fn list_shares(&self) -> Result<Vec<Share>> {
+        Share::list_all(&self.db).await
+    }
+
+    async fn shares_for_token(&self, token_id: &Uuid) -> Result<Vec<Share>> {
+        Share::list_by_token(token_id, &self.db).await
+    }
+
+    async fn find_share(&self, share: &str, token_id: &Uuid) -> Result<Share> {
+        Share::find_by_token(share, token_id, &self.db).await
+    }
+
+    async fn create_share(&self, actor: &Actor, name: &str) -> Result<Share> {
+        let mut tx = self.db.begin().await?;
+        let share = Share::create(name, &mut tx).await?;
+        let entry = actor.entry(
+            "create_share",
+            "share",
+            &share.id,
+            None,
+            Some(json!({ "name" : name })),
+        );
+        AdminAudit::record(&entry, &mut tx).await?;
+        tx.commit().await?;
+        Ok(share)
+    }
+
+    async fn list_schemas(&self) -> Result<Vec<Schema>> {
+        Schema::list_all(&self.db).await
+    }
+
+    async fn schemas_for_token(&self, share: &str, token_id: &Uuid) -> Result<Vec<Schema>> {
+        Schema::list_by_token(share, token_id, &self.db).await
+    }
+
+    async fn find_schema(&self, share: &str, schema: &str) -> Result<Schema> {
+        Schema::find(share, schema, &self.db).await
+    }
+
+    async fn create_schema(&self, actor: &Actor, name: &str, share_id: &Uuid) -> Result<Schema> {
+        let mut tx = self.db.begin().await?;
+        let schema = Schema::create(name, share_id, &mut tx).await?;
+        let entry = actor.entry(
+            "create_schema",
+            "schema",
+            &schema.id,
+            None,
+            Some(json!({
+                "name" : name,
+                "share_id" : share_id,
+            })),
+        );
+        AdminAudit::record(&entry, &mut tx).await?;
+        tx.commit().await?;
+        Ok(schema)
+    }
+
+    async fn list_tables(&self) -> Result<Vec<Table>> {
+        Table::list_all(&self.db).await
+    }
+
+    async fn tables_for_token(
+        &self,
+        share: &str,
+        schema: &str,
+        token_id: &Uuid,
+    ) -> Result<Vec<Table>> {
+        Table::list_by_token(share, schema, token_id, &self.db).await
+    }
+
+    async fn all_tables_for_token(&self, share: &str, token_id: &Uuid) -> Result<Vec<Table>> {
+        Table::list_by_share(share, token_id, &self.db).await
+    }
+
+    async fn find_table(
+        &self,
+        share: &str,
+        schema: &str,
+        table: &str,
+        token_id: &Uuid,
+    ) -> Result<Table> {
+        Table::find(share, schema, table, token_id, &self.db).await
+    }
+
+    async fn create_table(
+        &self,
+        actor: &Actor,
+        name: &str,
+        location: &str,
+        schema_id: &Uuid,
+    ) -> Result<Table> {
+        let mut tx = self.db.begin().await?;
+        let table = Table::create(name, location, schema_id, &mut tx).await?;
+        let entry = actor.entry(
+            "create_table",
+            "table",
+            table.id(),
+            None,
+            Some(json!({
+                "name" : name,
+                "location" : location,
+                "schema_id" : schema_id,
+            })),
+        );
+        AdminAudit::record(&entry, &mut tx).await?;
+        tx.commit().await?;
+        Ok(table)
+    }
+
+    async fn describe_table(
+        &self,
+        actor: &Actor,
+        id: &Uuid,
+        description: Option<&str>,
+    ) -> Result<Table> {
+        let mut tx = self.db.begin().await?;
+        let old = Table::description_for_update(id, &mut tx).await?;
+        let table = Table::describe(id, description, &mut tx).await?;
+        let entry = actor.entry(
+            "describe_table",
+            "table",
+            id,
+            Some(json!({ "description" : old })),
+            Some(json!({ "description" : description })),
+        );
+        AdminAudit::record(&entry, &mut tx).await?;
+        tx.commit().await?;
+        Ok(table)
+    }
+
+    async fn list_tokens(&self) -> Result<Vec<Token>> {
+        Token::list_all(&self.db).await
+    }
+
+    async fn token(&self, id: &Uuid) -> Result<Token> {
+        Token::by_id(id, &self.db).await
+    }
+
+    async fn authenticate(&self, secret: &str) -> Result<Option<Tokened>> {
+        Token::authenticate(secret, &self.db).await
+    }
+
+    async fn token_for_recipient(&self, recipient: &str) -> Result<Option<Tokened>> {
+        Token::for_recipient(recipient, &self.db).await
+    }
+
+    async fn create_token(&self, actor: &Actor, name: &str, tables: &[Uuid]) -> Result<Token> {
+        let mut tx = self.db.begin().await?;
+        let token = Token::generate(name, tables, &mut tx).await?;
+        // The secret itself must never end up in the audit trail
+        let entry = actor.entry(
+            "create_token",
+            "token",
+            &token.id,
+            None,
+            Some(json!({
+                "name" : name,
+                "tables" : tables,
+            })),
+        );
+        AdminAudit::record(&entry, &mut tx).await?;
+        tx.commit().await?;
+        Ok(token)
+    }
+
+    async fn revoke_token(&self, actor: &Actor, id: &Uuid) -> Result<Token> {
+        let mut tx = self.db.begin().await?;
+        let old = Token::by_id(id, &mut tx).await?;
+        let token = Token::revoke(id, &mut tx).await?;
+        let entry = actor.entry(
+            "revoke_token",
+            "token",
+            id,
+            Some(json!({ "expires_at" : old.expires_at })),
+            Some(json!({ "expires_at" : token.expires_at })),
+        );
+        AdminAudit::record(&entry, &mut tx).await?;
+        tx.commit().await?;
+        Ok(token)
+    }
+
+    async fn audit_trail(
+        &self,
+        object_type: Option<&str>,
+        object_id: Option<&Uuid>,
+    ) -> Result<Vec<AdminAudit>> {
+        AdminAudit::list(object_type, object_id, &self.db).await
+    }
+
+    async fn migrate(&self) -> Result<()> {
+        Ok(MIGRATOR.run(&self.db).await?)
+    }
+}
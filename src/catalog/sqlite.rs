@@ -0,0 +1,731 @@
+/*
+ * The SQLite catalog keeps the catalog in a single file, for small single
+ * node deployments.
+ *
+ * The queries are checked at runtime rather than by the sqlx macros, since
+ * the offline query data only covers Postgres
+ */
+use chrono::{DateTime, Duration, Utc};
+use serde_json::json;
+use sqlx::sqlite::{SqliteConnection, SqlitePool, SqlitePoolOptions};
+use uuid::Uuid;
+
+use super::{CatalogStore, Result};
+use crate::models::{Actor, AdminAudit, AuditEntry, PrimitiveTable, Schema, Share, Table, Token};
+use crate::state::Tokened;
+
+/// The migrations in `./migrations-sqlite`, embedded at build time
+pub static MIGRATOR: sqlx::migrate::Migrator = sqlx::migrate!("./migrations-sqlite");
+
+const SELECT_SCHEMAS: &str = "SELECT schemas.*, shares.name AS share_name FROM schemas, shares
+    WHERE schemas.share_id = shares.id";
+
+const GRANTED: &str = "SELECT table_id FROM tokens_for_tables WHERE token_id = ?";
+
+#[derive(Clone, Debug)]
+pub struct SqliteCatalog {
+    db: SqlitePool,
+}
+
+impl SqliteCatalog {
+    /**
+     * Open the database at the URL, e.g. `sqlite://riverbank.db?mode=rwc`,
+     * and apply the pending migrations
+     */
+    pub async fn connect(url: &str) -> Result<Self> {
+        let db = SqlitePoolOptions::new().connect(url).await?;
+        MIGRATOR.run(&db).await?;
+        Ok(Self { db })
+    }
+}
+
+async fn schema_by_id<'e, E>(id: &Uuid, db: E) -> Result<Schema>
+where
+    E: sqlx::Executor<'e, Database = sqlx::Sqlite>,
+{
+    sqlx::query_as(&format!("{} AND schemas.id = ?", SELECT_SCHEMAS))
+        .bind(id)
+        .fetch_one(db)
+        .await
+}
+
+/**
+ * Write the entry to the audit trail, in the transaction of the change
+ */
+async fn record(entry: &AuditEntry, conn: &mut SqliteConnection) -> Result<()> {
+    sqlx::query(
+        "INSERT INTO admin_audit
+            (id, actor, action, object_type, object_id, old_value, new_value, source_ip, created_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(Uuid::new_v4())
+    .bind(&entry.actor)
+    .bind(entry.action)
+    .bind(entry.object_type)
+    .bind(entry.object_id)
+    .bind(entry.old_value.as_ref().map(|v| v.to_string()))
+    .bind(entry.new_value.as_ref().map(|v| v.to_string()))
+    .bind(&entry.source_ip)
+    .bind(Utc::now())
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// An entry of the audit trail, whose values are kept as JSON text
+#[derive(sqlx::FromRow)]
+struct AuditRow {
+    id: Uuid,
+    actor: String,
+    action: String,
+    object_type: String,
+    object_id: Option<Uuid>,
+    old_value: Option<String>,
+    new_value: Option<String>,
+    source_ip: Option<String>,
+    created_at: DateTime<Utc>,
+}
+
+impl AuditRow {
+    fn decode(self) -> Result<AdminAudit> {
+        let parse = |value: Option<String>| {
+            value
+                .map(|v| serde_json::from_str(&v))
+                .transpose()
+                .map_err(|e| sqlx::Error::Decode(e.into()))
+        };
+        Ok(AdminAudit {
+            id: self.id,
+            actor: self.actor,
+            action: self.action,
+            object_type: self.object_type,
+            object_id: self.object_id,
+            old_value: parse(self.old_value)?,
+            new_value: parse(self.new_value)?,
+            source_ip: self.source_ip,
+            created_at: self.created_at,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl CatalogStore for SqliteCatalog {
+    async fn list_shares(&self) -> Result<Vec<Share>> {
+        sqlx::query_as("SELECT * FROM shares ORDER BY created_at")
+            .fetch_all(&self.db)
+            .await
+    }
+
+    async fn shares_for_token(&self, token_id: &Uuid) -> Result<Vec<Share>> {
+        sqlx::query_as(&format!(
+            "SELECT DISTINCT shares.* FROM shares, schemas, tables
+                WHERE shares.id = schemas.share_id
+                AND schemas.id = tables.schema_id
+                AND tables.id IN ({})
+                ORDER BY shares.name, shares.id",
+            GRANTED
+        ))
+        .bind(token_id)
+        .fetch_all(&self.db)
+        .await
+    }
+
+    async fn find_share(&self, share: &str, token_id: &Uuid) -> Result<Share> {
+        sqlx::query_as(&format!(
+            "SELECT DISTINCT shares.* FROM shares, schemas, tables
+                WHERE shares.name = ?
+                AND shares.id = schemas.share_id
+                AND schemas.id = tables.schema_id
+                AND tables.id IN ({})",
+            GRANTED
+        ))
+        .bind(share)
+        .bind(token_id)
+        .fetch_one(&self.db)
+        .await
+    }
+
+    async fn create_share(&self, actor: &Actor, name: &str) -> Result<Share> {
+        let mut tx = self.db.begin().await?;
+        let share: Share = sqlx::query_as(
+            "INSERT INTO shares (id, name, created_at) VALUES (?, ?, ?) RETURNING *",
+        )
+        .bind(Uuid::new_v4())
+        .bind(name)
+        .bind(Utc::now())
+        .fetch_one(&mut tx)
+        .await?;
+        let entry = actor.entry(
+            "create_share",
+            "share",
+            &share.id,
+            None,
+            Some(json!({ "name" : name })),
+        );
+        record(&entry, &mut tx).await?;
+        tx.commit().await?;
+        Ok(share)
+    }
+
+    async fn list_schemas(&self) -> Result<Vec<Schema>> {
+        sqlx::query_as(&format!("{} ORDER BY schemas.created_at", SELECT_SCHEMAS))
+            .fetch_all(&self.db)
+            .await
+    }
+
+    async fn schemas_for_token(&self, share: &str, token_id: &Uuid) -> Result<Vec<Schema>> {
+        sqlx::query_as(&format!(
+            "{} AND shares.name = ?
+                AND schemas.id IN (SELECT schema_id FROM tables WHERE id IN ({}))
+                ORDER BY schemas.name, schemas.id",
+            SELECT_SCHEMAS, GRANTED
+        ))
+        .bind(share)
+        .bind(token_id)
+        .fetch_all(&self.db)
+        .await
+    }
+
+    async fn find_schema(&self, share: &str, schema: &str) -> Result<Schema> {
+        sqlx::query_as(&format!(
+            "{} AND shares.name = ? AND schemas.name = ?",
+            SELECT_SCHEMAS
+        ))
+        .bind(share)
+        .bind(schema)
+        .fetch_one(&self.db)
+        .await
+    }
+
+    async fn create_schema(&self, actor: &Actor, name: &str, share_id: &Uuid) -> Result<Schema> {
+        let mut tx = self.db.begin().await?;
+        let id = Uuid::new_v4();
+        sqlx::query(
+            "INSERT INTO schemas (id, name, share_id, created_at)
+                SELECT ?, ?, id, ? FROM shares WHERE id = ?",
+        )
+        .bind(id)
+        .bind(name)
+        .bind(Utc::now())
+        .bind(share_id)
+        .execute(&mut tx)
+        .await?;
+        let schema = schema_by_id(&id, &mut tx).await?;
+        let entry = actor.entry(
+            "create_schema",
+            "schema",
+            &id,
+            None,
+            Some(json!({
+                "name" : name,
+                "share_id" : share_id,
+            })),
+        );
+        record(&entry, &mut tx).await?;
+        tx.commit().await?;
+        Ok(schema)
+    }
+
+    async fn list_tables(&self) -> Result<Vec<Table>> {
+        let schemas = self.list_schemas().await?;
+        let tables: Vec<PrimitiveTable> =
+            sqlx::query_as("SELECT * FROM tables ORDER BY created_at")
+                .fetch_all(&self.db)
+                .await?;
+
+        tables
+            .into_iter()
+            .map(|inner| {
+                let schema = schemas
+                    .iter()
+                    .find(|schema| schema.id == inner.schema_id)
+                    .ok_or(sqlx::Error::RowNotFound)?;
+                Ok(Table::new(inner, schema.clone()))
+            })
+            .collect()
+    }
+
+    async fn tables_for_token(
+        &self,
+        share: &str,
+        schema: &str,
+        token_id: &Uuid,
+    ) -> Result<Vec<Table>> {
+        let schema = self.find_schema(share, schema).await?;
+        let tables: Vec<PrimitiveTable> = sqlx::query_as(&format!(
+            "SELECT * FROM tables WHERE schema_id = ? AND id IN ({}) ORDER BY name, id",
+            GRANTED
+        ))
+        .bind(schema.id)
+        .bind(token_id)
+        .fetch_all(&self.db)
+        .await?;
+
+        Ok(tables
+            .into_iter()
+            .map(|inner| Table::new(inner, schema.clone()))
+            .collect())
+    }
+
+    async fn all_tables_for_token(&self, share: &str, token_id: &Uuid) -> Result<Vec<Table>> {
+        let share = self.find_share(share, token_id).await?;
+        let schemas = self.list_schemas().await?;
+        let tables: Vec<PrimitiveTable> = sqlx::query_as(&format!(
+            "SELECT tables.* FROM tables, schemas
+                WHERE tables.schema_id = schemas.id
+                AND schemas.share_id = ?
+                AND tables.id IN ({})
+                ORDER BY schemas.name, tables.name, tables.id",
+            GRANTED
+        ))
+        .bind(share.id)
+        .bind(token_id)
+        .fetch_all(&self.db)
+        .await?;
+
+        tables
+            .into_iter()
+            .map(|inner| {
+                let schema = schemas
+                    .iter()
+                    .find(|schema| schema.id == inner.schema_id)
+                    .ok_or(sqlx::Error::RowNotFound)?;
+                Ok(Table::new(inner, schema.clone()))
+            })
+            .collect()
+    }
+
+    async fn find_table(
+        &self,
+        share: &str,
+        schema: &str,
+        table: &str,
+        token_id: &Uuid,
+    ) -> Result<Table> {
+        let schema = self.find_schema(share, schema).await?;
+        let inner: PrimitiveTable = sqlx::query_as(&format!(
+            "SELECT * FROM tables WHERE schema_id = ? AND name = ? AND id IN ({})",
+            GRANTED
+        ))
+        .bind(schema.id)
+        .bind(table)
+        .bind(token_id)
+        .fetch_one(&self.db)
+        .await?;
+        Ok(Table::new(inner, schema))
+    }
+
+    async fn create_table(
+        &self,
+        actor: &Actor,
+        name: &str,
+        location: &str,
+        schema_id: &Uuid,
+    ) -> Result<Table> {
+        let mut tx = self.db.begin().await?;
+        let schema = schema_by_id(schema_id, &mut tx).await?;
+        let inner: PrimitiveTable = sqlx::query_as(
+            "INSERT INTO tables (id, name, location, schema_id, created_at)
+                VALUES (?, ?, ?, ?, ?) RETURNING *",
+        )
+        .bind(Uuid::new_v4())
+        .bind(name)
+        .bind(location)
+        .bind(schema.id)
+        .bind(Utc::now())
+        .fetch_one(&mut tx)
+        .await?;
+        let entry = actor.entry(
+            "create_table",
+            "table",
+            &inner.id,
+            None,
+            Some(json!({
+                "name" : name,
+                "location" : location,
+                "schema_id" : schema_id,
+            })),
+        );
+        record(&entry, &mut tx).await?;
+        tx.commit().await?;
+        Ok(Table::new(inner, schema))
+    }
+
+    async fn describe_table(
+        &self,
+        actor: &Actor,
+        id: &Uuid,
+        description: Option<&str>,
+    ) -> Result<Table> {
+        let mut tx = self.db.begin().await?;
+        let old: Option<String> = sqlx::query_scalar("SELECT description FROM tables WHERE id = ?")
+            .bind(id)
+            .fetch_one(&mut tx)
+            .await?;
+        let inner: PrimitiveTable =
+            sqlx::query_as("UPDATE tables SET description = ? WHERE id = ? RETURNING *")
+                .bind(description)
+                .bind(id)
+                .fetch_one(&mut tx)
+                .await?;
+        let schema = schema_by_id(&inner.schema_id, &mut tx).await?;
+        let entry = actor.entry(
+            "describe_table",
+            "table",
+            id,
+            Some(json!({ "description" : old })),
+            Some(json!({ "description" : description })),
+        );
+        record(&entry, &mut tx).await?;
+        tx.commit().await?;
+        Ok(Table::new(inner, schema))
+    }
+
+    async fn list_tokens(&self) -> Result<Vec<Token>> {
+        sqlx::query_as("SELECT * FROM tokens WHERE expires_at > ? ORDER BY created_at")
+            .bind(Utc::now())
+            .fetch_all(&self.db)
+            .await
+    }
+
+    async fn token(&self, id: &Uuid) -> Result<Token> {
+        sqlx::query_as("SELECT * FROM tokens WHERE id = ?")
+            .bind(id)
+            .fetch_one(&self.db)
+            .await
+    }
+
+    async fn authenticate(&self, secret: &str) -> Result<Option<Tokened>> {
+        sqlx::query_as("SELECT id, name, token FROM tokens WHERE token = ? AND expires_at > ?")
+            .bind(secret)
+            .bind(Utc::now())
+            .fetch_optional(&self.db)
+            .await
+    }
+
+    async fn token_for_recipient(&self, recipient: &str) -> Result<Option<Tokened>> {
+        sqlx::query_as(
+            "SELECT id, name, token FROM tokens
+                WHERE name = ? AND expires_at > ?
+                ORDER BY created_at DESC LIMIT 1",
+        )
+        .bind(recipient)
+        .bind(Utc::now())
+        .fetch_optional(&self.db)
+        .await
+    }
+
+    async fn create_token(&self, actor: &Actor, name: &str, tables: &[Uuid]) -> Result<Token> {
+        let mut tx = self.db.begin().await?;
+        let now = Utc::now();
+        let token: Token = sqlx::query_as(
+            "INSERT INTO tokens (id, name, token, expires_at, created_at)
+                VALUES (?, ?, ?, ?, ?) RETURNING *",
+        )
+        .bind(Uuid::new_v4())
+        .bind(name)
+        .bind(Uuid::new_v4().to_hyphenated().to_string())
+        .bind(now + Duration::days(30))
+        .bind(now)
+        .fetch_one(&mut tx)
+        .await?;
+
+        for table in tables {
+            sqlx::query(
+                "INSERT INTO tokens_for_tables (token_id, table_id, created_at) VALUES (?, ?, ?)",
+            )
+            .bind(token.id)
+            .bind(table)
+            .bind(now)
+            .execute(&mut tx)
+            .await?;
+        }
+        // The secret itself must never end up in the audit trail
+        let entry = actor.entry(
+            "create_token",
+            "token",
+            &token.id,
+            None,
+            Some(json!({
+                "name" : name,
+                "tables" : tables,
+            })),
+        );
+        record(&entry, &mut tx).await?;
+        tx.commit().await?;
+        Ok(token)
+    }
+
+    async fn revoke_token(&self, actor: &Actor, id: &Uuid) -> Result<Token> {
+        let mut tx = self.db.begin().await?;
+        let now = Utc::now();
+        let old: Token = sqlx::query_as("SELECT * FROM tokens WHERE id = ?")
+            .bind(id)
+            .fetch_one(&mut tx)
+            .await?;
+        let token: Token = sqlx::query_as(
+            "UPDATE tokens SET expires_at = ? WHERE id = ? AND expires_at > ? RETURNING *",
+        )
+        .bind(now)
+        .bind(id)
+        .bind(now)
+        .fetch_one(&mut tx)
+        .await?;
+        let entry = actor.entry(
+            "revoke_token",
+            "token",
+            id,
+            Some(json!({ "expires_at" : old.expires_at })),
+            Some(json!({ "expires_at" : token.expires_at })),
+        );
+        record(&entry, &mut tx).await?;
+        tx.commit().await?;
+        Ok(token)
+    }
+
+    async fn audit_trail(
+        &self,
+        object_type: Option<&str>,
+        object_id: Option<&Uuid>,
+    ) -> Result<Vec<AdminAudit>> {
+        let rows: Vec<AuditRow> = sqlx::query_as(
+            "SELECT * FROM admin_audit
+                WHERE (? IS NULL OR object_type = ?)
+                AND (? IS NULL OR object_id = ?)
+                ORDER BY created_at DESC, rowid DESC
+                LIMIT 500",
+        )
+        .bind(object_type)
+        .bind(object_type)
+        .bind(object_id)
+        .bind(object_id)
+        .fetch_all(&self.db)
+        .await?;
+        rows.into_iter().map(AuditRow::decode).collect()
+    }
+
+    async fn migrate(&self) -> Result<()> {
+        Ok(MIGRATOR.run(&self.db).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn actor() -> Actor {
+        Actor {
+            name: "admin".to_string(),
+            source_ip: None,
+        }
+    }
+
+    /**
+     * A catalog in a fresh in-memory database with two tables in the same
+     * schema, of which the returned token is only granted the first
+     */
+    async fn catalog() -> (SqliteCatalog, Token) {
+        // Every connection to `sqlite::memory:` opens a database of its own,
+        // so the pool must keep hold of a single one
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        MIGRATOR.run(&db).await.unwrap();
+        let catalog = SqliteCatalog { db };
+
+        let share = catalog
+            .create_share(&actor(), "vaccine_share")
+            .await
+            .unwrap();
+        let schema = catalog
+            .create_schema(&actor(), "acme_vaccine_data", &share.id)
+            .await
+            .unwrap();
+        let granted = catalog
+            .create_table(
+                &actor(),
+                "vaccine_ingredients",
+                "s3://delta/ingredients",
+                &schema.id,
+            )
+            .await
+            .unwrap();
+        catalog
+            .create_table(
+                &actor(),
+                "vaccine_patients",
+                "s3://delta/patients",
+                &schema.id,
+            )
+            .await
+            .unwrap();
+        let token = catalog
+            .create_token(&actor(), "acme", &[granted.inner.id])
+            .await
+            .unwrap();
+        (catalog, token)
+    }
+
+    #[async_std::test]
+    async fn test_grants() {
+        let (catalog, token) = catalog().await;
+
+        assert_eq!(1, catalog.shares_for_token(&token.id).await.unwrap().len());
+        assert_eq!(
+            1,
+            catalog
+                .schemas_for_token("vaccine_share", &token.id)
+                .await
+                .unwrap()
+                .len()
+        );
+        let tables = catalog
+            .tables_for_token("vaccine_share", "acme_vaccine_data", &token.id)
+            .await
+            .unwrap();
+        assert_eq!(
+            vec!["vaccine_ingredients"],
+            tables.iter().map(Table::name).collect::<Vec<_>>()
+        );
+
+        assert!(catalog
+            .find_table(
+                "vaccine_share",
+                "acme_vaccine_data",
+                "vaccine_patients",
+                &token.id
+            )
+            .await
+            .is_err());
+        assert!(catalog
+            .shares_for_token(&Uuid::new_v4())
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_all_tables() {
+        let (catalog, token) = catalog().await;
+
+        let share = catalog
+            .find_share("vaccine_share", &token.id)
+            .await
+            .unwrap();
+        assert_eq!("vaccine_share", share.name);
+        assert!(catalog
+            .find_share("vaccine_share", &Uuid::new_v4())
+            .await
+            .is_err());
+
+        let tables = catalog
+            .all_tables_for_token("vaccine_share", &token.id)
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![("acme_vaccine_data", "vaccine_ingredients")],
+            tables
+                .iter()
+                .map(|t| (t.schema(), t.name()))
+                .collect::<Vec<_>>()
+        );
+        assert!(catalog
+            .all_tables_for_token("other_share", &token.id)
+            .await
+            .is_err());
+    }
+
+    #[async_std::test]
+    async fn test_describe_table() {
+        let (catalog, token) = catalog().await;
+        let table = catalog
+            .find_table(
+                "vaccine_share",
+                "acme_vaccine_data",
+                "vaccine_ingredients",
+                &token.id,
+            )
+            .await
+            .unwrap();
+
+        let described = catalog
+            .describe_table(&actor(), table.id(), Some("Ingredients"))
+            .await
+            .unwrap();
+        assert_eq!(Some("Ingredients".to_string()), described.inner.description);
+        let cleared = catalog
+            .describe_table(&actor(), table.id(), None)
+            .await
+            .unwrap();
+        assert_eq!(None, cleared.inner.description);
+        assert!(catalog
+            .describe_table(&actor(), &Uuid::new_v4(), None)
+            .await
+            .is_err());
+    }
+
+    #[async_std::test]
+    async fn test_revoke() {
+        let (catalog, token) = catalog().await;
+
+        let found = catalog.authenticate(&token.token).await.unwrap();
+        assert_eq!(Some(token.id), found.map(|t| t.id));
+
+        catalog.revoke_token(&actor(), &token.id).await.unwrap();
+        assert!(catalog.authenticate(&token.token).await.unwrap().is_none());
+        assert!(catalog.list_tokens().await.unwrap().is_empty());
+        assert!(catalog.revoke_token(&actor(), &token.id).await.is_err());
+    }
+
+    #[async_std::test]
+    async fn test_audit_trail() {
+        let (catalog, token) = catalog().await;
+        assert_eq!(5, catalog.audit_trail(None, None).await.unwrap().len());
+
+        let table = catalog
+            .find_table(
+                "vaccine_share",
+                "acme_vaccine_data",
+                "vaccine_ingredients",
+                &token.id,
+            )
+            .await
+            .unwrap();
+        catalog
+            .describe_table(&actor(), table.id(), Some("Ingredients"))
+            .await
+            .unwrap();
+        catalog
+            .describe_table(&actor(), table.id(), None)
+            .await
+            .unwrap();
+
+        let history = catalog
+            .audit_trail(Some("table"), Some(table.id()))
+            .await
+            .unwrap();
+        assert_eq!(
+            vec!["describe_table", "describe_table", "create_table"],
+            history
+                .iter()
+                .map(|e| e.action.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            Some(json!({ "description" : "Ingredients" })),
+            history[0].old_value
+        );
+        assert_eq!(Some(json!({ "description" : null })), history[0].new_value);
+
+        // A failed change leaves no entry behind
+        assert!(catalog
+            .revoke_token(&actor(), &Uuid::new_v4())
+            .await
+            .is_err());
+        assert_eq!(8, catalog.audit_trail(None, None).await.unwrap().len());
+    }
+}
@@ -0,0 +1,268 @@
+/*
+ * The cli module parses the command line and runs the administrative
+ * subcommands, which work directly against the configured catalog
+ */
+use sqlx::PgPool;
+use std::path::PathBuf;
+use structopt::StructOpt;
+use uuid::Uuid;
+
+use crate::catalog::{parse_table_ref, CatalogStore};
+use crate::config::Config;
+use crate::models::Actor;
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "riverbank", about = "A Delta Sharing server")]
+pub struct Opts {
+    /// The configuration file to load
+    #[structopt(short, long, default_value = "config.yml", parse(from_os_str))]
+    pub config: PathBuf,
+    #[structopt(flatten)]
+    pub overrides: Overrides,
+    /// What to do, serving requests when omitted
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+/**
+ * Settings given on the command line, which take precedence over both the
+ * configuration file and the environment
+ */
+#[derive(Clone, Debug, Default, StructOpt)]
+pub struct Overrides {
+    /// The address to listen on, e.g. 0.0.0.0:8000
+    #[structopt(long)]
+    pub bind: Option<String>,
+    /// The client-facing URL of the sharing API
+    #[structopt(long)]
+    pub public_url: Option<String>,
+}
+
+impl Overrides {
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(bind) = &self.bind {
+            config.server.bind = bind.clone();
+        }
+        if let Some(url) = &self.public_url {
+            config.public_url = url.clone();
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    /// Serve the sharing API and the admin console
+    Serve,
+    /// Apply the pending migrations of the catalog and the database
+    Migrate,
+    /// Manage shares
+    Share(ShareCommand),
+    /// Manage tables
+    Table(TableCommand),
+    /// Manage the bearer tokens of recipients
+    Token(TokenCommand),
+    /// Export Delta Sharing profile files for recipients
+    Profile(ProfileCommand),
+}
+
+#[derive(Debug, StructOpt)]
+pub enum ShareCommand {
+    /// Create an empty share
+    Create { name: String },
+}
+
+#[derive(Debug, StructOpt)]
+pub enum TableCommand {
+    /// Add a Delta table to a schema, creating the schema if needed
+    Add {
+        #[structopt(long)]
+        share: String,
+        #[structopt(long)]
+        schema: String,
+        name: String,
+        /// Where the Delta table is stored, e.g. `s3://bucket/path`
+        location: String,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+pub enum TokenCommand {
+    /// Create a token granting access to the tables, printing its id and secret
+    Create {
+        name: String,
+        /// The tables to grant access to, as `share.schema.table`
+        #[structopt(long, required = true, min_values = 1)]
+        tables: Vec<String>,
+    },
+    /// Revoke a token, after which it can no longer be used
+    Revoke { id: Uuid },
+}
+
+#[derive(Debug, StructOpt)]
+pub enum ProfileCommand {
+    /// Write the profile file of a token to stdout or a file
+    Export {
+        /// The id of the token
+        token: Uuid,
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+}
+
+/**
+ * Find the ids of the referenced tables, failing on the first unknown one
+ */
+async fn resolve_tables(
+    references: &[String],
+    catalog: &dyn CatalogStore,
+) -> Result<Vec<Uuid>, tide::Error> {
+    let tables = catalog.list_tables().await?;
+    let mut ids = vec![];
+
+    for reference in references {
+        let (share, schema, name) =
+            parse_table_ref(reference).map_err(|e| tide::Error::from_str(400, e))?;
+        let found = tables
+            .iter()
+            .find(|t| t.share() == share && t.schema() == schema && t.name() == name)
+            .ok_or_else(|| tide::Error::from_str(404, format!("No table named {}", reference)))?;
+        ids.push(found.inner.id);
+    }
+    Ok(ids)
+}
+
+/**
+ * The user running the command, whose changes the catalog records in its
+ * audit trail next to the ones made in the admin console
+ */
+fn actor() -> Actor {
+    Actor {
+        name: format!(
+            "cli:{}",
+            std::env::var("USER").unwrap_or_else(|_| "unknown".to_string())
+        ),
+        source_ip: None,
+    }
+}
+
+/**
+ * Run an administrative subcommand, printing its result. The database is only
+ * needed when the catalog is kept in Postgres, or for migrating it
+ */
+pub async fn run(
+    command: Command,
+    config: &Config,
+    catalog: &dyn CatalogStore,
+    db: Option<&PgPool>,
+) -> Result<(), tide::Error> {
+    match command {
+        Command::Serve => unreachable!("Serving is handled by main"),
+        Command::Migrate => {
+            catalog.migrate().await?;
+            // The access log, usage counters and webhooks are kept in
+            // Postgres wherever the catalog is
+            if let Some(db) = db.filter(|_| !config.catalog.is_postgres()) {
+                crate::models::MIGRATOR.run(db).await?;
+            }
+            println!("The catalog is up to date");
+        }
+        Command::Share(ShareCommand::Create { name }) => {
+            let share = catalog.create_share(&actor(), &name).await?;
+            println!("Created share {} with id {}", share.name, share.id);
+        }
+        Command::Table(TableCommand::Add {
+            share,
+            schema,
+            name,
+            location,
+        }) => {
+            let schema = match catalog.find_schema(&share, &schema).await {
+                Ok(found) => found,
+                Err(sqlx::Error::RowNotFound) => {
+                    let found = catalog
+                        .list_shares()
+                        .await?
+                        .into_iter()
+                        .find(|s| s.name == share)
+                        .ok_or_else(|| {
+                            tide::Error::from_str(404, format!("No share named {}", share))
+                        })?;
+                    catalog.create_schema(&actor(), &schema, &found.id).await?
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let table = catalog
+                .create_table(&actor(), &name, &location, &schema.id)
+                .await?;
+            println!(
+                "Added table {}.{}.{} with id {}",
+                table.share(),
+                table.schema(),
+                table.name(),
+                table.inner.id
+            );
+        }
+        Command::Token(TokenCommand::Create { name, tables }) => {
+            let tables = resolve_tables(&tables, catalog).await?;
+            let token = catalog.create_token(&actor(), &name, &tables).await?;
+            println!("Created token {} with id {}", token.name, token.id);
+            println!("{}", token.token);
+        }
+        Command::Token(TokenCommand::Revoke { id }) => {
+            let token = catalog
+                .revoke_token(&actor(), &id)
+                .await
+                .map_err(|e| match e {
+                    sqlx::Error::RowNotFound => {
+                        tide::Error::from_str(404, format!("No active token with id {}", id))
+                    }
+                    e => e.into(),
+                })?;
+            println!("Revoked token {} with id {}", token.name, token.id);
+        }
+        Command::Profile(ProfileCommand::Export { token, output }) => {
+            // The file catalog only knows the digests of the tokens
+            if config.catalog.is_read_only() {
+                return Err(tide::Error::from_str(
+                    400,
+                    "Profiles cannot be exported from the file catalog, which does not keep the tokens themselves",
+                ));
+            }
+            let token = catalog.token(&token).await?;
+            let profile = serde_json::to_string_pretty(&token.profile(&config.public_url))?;
+            match output {
+                Some(path) => std::fs::write(path, profile)?,
+                None => println!("{}", profile),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_create_args() {
+        let opts = Opts::from_iter(&[
+            "riverbank",
+            "--config",
+            "/etc/riverbank.yml",
+            "token",
+            "create",
+            "acme",
+            "--tables",
+            "a.b.c",
+            "d.e.f",
+        ]);
+        assert_eq!(PathBuf::from("/etc/riverbank.yml"), opts.config);
+        match opts.command {
+            Some(Command::Token(TokenCommand::Create { name, tables })) => {
+                assert_eq!("acme", name);
+                assert_eq!(vec!["a.b.c", "d.e.f"], tables);
+            }
+            other => panic!("Unexpected command {:?}", other),
+        }
+    }
+}
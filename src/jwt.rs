@@ -0,0 +1,366 @@
+/*
+ * The jwt module is responsible for validating JSON Web Tokens against the
+ * keys published in a JSON Web Key Set (JWKS)
+ */
+use async_std::sync::RwLock;
+use jsonwebtoken::errors::{Error, ErrorKind};
+use jsonwebtoken::{Algorithm, DecodingKey, TokenData, Validation};
+use log::*;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use crate::config::JwtIssuerConfig;
+
+/**
+ * A single RSA key from a JWKS document
+ */
+#[derive(Clone, Debug, Deserialize)]
+pub struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+impl JwkSet {
+    /**
+     * Find the key matching the `kid` of a token header. Tokens without a
+     * `kid` can only be matched when the set contains exactly one key
+     */
+    fn find(&self, kid: Option<&str>) -> Option<&Jwk> {
+        match kid {
+            Some(kid) => self.keys.iter().find(|k| k.kid.as_deref() == Some(kid)),
+            None if self.keys.len() == 1 => self.keys.first(),
+            None => None,
+        }
+    }
+}
+
+/**
+ * Where a key set is loaded from
+ */
+#[derive(Clone, Debug)]
+pub enum JwksSource {
+    Url(String),
+    File(PathBuf),
+}
+
+impl std::fmt::Display for JwksSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JwksSource::Url(url) => write!(f, "{}", url),
+            JwksSource::File(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+impl JwksSource {
+    async fn load(&self) -> Result<JwkSet, tide::Error> {
+        match self {
+            JwksSource::Url(url) => surf::get(url).recv_json().await,
+            JwksSource::File(path) => {
+                let contents = async_std::fs::read_to_string(path).await?;
+                Ok(serde_json::from_str(&contents)?)
+            }
+        }
+    }
+}
+
+/// The least time between two reloads of a key set, so that tokens with made
+/// up key ids cannot turn every request into a fetch from the issuer
+const REFRESH_COOLDOWN: Duration = Duration::from_secs(60);
+
+/**
+ * Jwks holds a key set loaded from a remote URL or a local file, reloading it
+ * when a token refers to a key which hasn't been seen yet, e.g. after the
+ * issuer rotated its signing keys
+ */
+#[derive(Debug)]
+pub struct Jwks {
+    source: JwksSource,
+    keys: RwLock<JwkSet>,
+    refreshed_at: std::sync::Mutex<Instant>,
+}
+
+impl Jwks {
+    pub async fn load(source: JwksSource) -> Result<Jwks, tide::Error> {
+        let keys = source.load().await?;
+        Ok(Jwks {
+            source,
+            keys: RwLock::new(keys),
+            refreshed_at: std::sync::Mutex::new(Instant::now()),
+        })
+    }
+
+    /**
+     * Whether the key set may be reloaded now, claiming the reload for the
+     * caller so that concurrent requests don't reload it as well
+     */
+    fn may_refresh(&self) -> bool {
+        let mut refreshed_at = self.refreshed_at.lock().unwrap();
+        if refreshed_at.elapsed() < REFRESH_COOLDOWN {
+            return false;
+        }
+        *refreshed_at = Instant::now();
+        true
+    }
+
+    pub async fn fetch(url: &str) -> Result<Jwks, tide::Error> {
+        Jwks::load(JwksSource::Url(url.to_string())).await
+    }
+
+    async fn refresh(&self) -> Result<(), tide::Error> {
+        debug!("Refreshing JWKS from {}", self.source);
+        let keys = self.source.load().await?;
+        *self.keys.write().await = keys;
+        Ok(())
+    }
+
+    /**
+     * Validate the signature and claims of the given token.
+     *
+     * Only RSA keys are supported, and the accepted algorithms are always
+     * taken from the `validation` rather than from the token itself
+     */
+    pub async fn decode<T: DeserializeOwned>(
+        &self,
+        token: &str,
+        validation: &Validation,
+    ) -> Result<TokenData<T>, Error> {
+        let header = jsonwebtoken::decode_header(token)?;
+
+        if self.keys.read().await.find(header.kid.as_deref()).is_none() && self.may_refresh() {
+            if let Err(e) = self.refresh().await {
+                error!("Failed to refresh JWKS from {}: {}", self.source, e);
+            }
+        }
+
+        let keys = self.keys.read().await;
+        let jwk = keys
+            .find(header.kid.as_deref())
+            .ok_or_else(|| Error::from(ErrorKind::InvalidRsaKey))?;
+
+        match (jwk.kty.as_str(), &jwk.n, &jwk.e) {
+            ("RSA", Some(n), Some(e)) => {
+                jsonwebtoken::decode(token, &DecodingKey::from_rsa_components(n, e), validation)
+            }
+            _ => Err(ErrorKind::InvalidRsaKey.into()),
+        }
+    }
+}
+
+/**
+ * A trusted issuer of JWTs which recipients may use as bearer tokens instead
+ * of the opaque tokens generated by riverbank
+ */
+#[derive(Debug)]
+pub struct JwtIssuer {
+    config: JwtIssuerConfig,
+    jwks: Jwks,
+}
+
+impl JwtIssuer {
+    pub async fn load(config: &JwtIssuerConfig) -> Result<JwtIssuer, tide::Error> {
+        if !is_rsa(&config.algorithm) {
+            return Err(tide::Error::from_str(
+                500,
+                format!(
+                    "Only RSA signing algorithms are supported for the issuer {}",
+                    config.issuer
+                ),
+            ));
+        }
+
+        let source = match (&config.jwks_url, &config.jwks_file) {
+            (Some(url), None) => JwksSource::Url(url.clone()),
+            (None, Some(path)) => JwksSource::File(path.clone()),
+            _ => {
+                return Err(tide::Error::from_str(
+                    500,
+                    format!(
+                        "Exactly one of jwks_url or jwks_file must be set for the issuer {}",
+                        config.issuer
+                    ),
+                ))
+            }
+        };
+
+        Ok(JwtIssuer {
+            config: config.clone(),
+            jwks: Jwks::load(source).await?,
+        })
+    }
+
+    /**
+     * Validate the token and return the name of the recipient found in the
+     * configured claim, which must be one the issuer may vouch for
+     */
+    pub async fn recipient(&self, token: &str) -> Result<String, Error> {
+        let mut validation = Validation::new(self.config.algorithm);
+        validation.iss = Some(self.config.issuer.clone());
+        validation.set_audience(&[&self.config.audience]);
+
+        let data = self
+            .jwks
+            .decode::<HashMap<String, Value>>(token, &validation)
+            .await?;
+
+        match data.claims.get(&self.config.recipient_claim) {
+            Some(Value::String(recipient)) if self.config.recipients.contains(recipient) => {
+                Ok(recipient.clone())
+            }
+            Some(Value::String(recipient)) => {
+                warn!(
+                    "The issuer {} is not allowed to issue tokens for {}",
+                    self.config.issuer, recipient
+                );
+                Err(ErrorKind::InvalidToken.into())
+            }
+            _ => Err(ErrorKind::InvalidToken.into()),
+        }
+    }
+}
+
+/**
+ * Find the configured issuer of the given token, without validating anything
+ * but the issuer claim
+ */
+pub fn issuer_of<'a>(token: &str, issuers: &'a [JwtIssuer]) -> Option<&'a JwtIssuer> {
+    #[derive(Deserialize)]
+    struct Unverified {
+        iss: Option<String>,
+    }
+
+    let iss = jsonwebtoken::dangerous_insecure_decode::<Unverified>(token)
+        .ok()?
+        .claims
+        .iss?;
+    issuers.iter().find(|issuer| issuer.config.issuer == iss)
+}
+
+/**
+ * Only RSA keys are supported, accepting any other algorithm would allow
+ * tokens "signed" with the public key as an HMAC secret
+ */
+pub fn is_rsa(algorithm: &Algorithm) -> bool {
+    matches!(
+        algorithm,
+        Algorithm::RS256
+            | Algorithm::RS384
+            | Algorithm::RS512
+            | Algorithm::PS256
+            | Algorithm::PS384
+            | Algorithm::PS512
+    )
+}
+
+/**
+ * Opaque riverbank tokens are UUIDs, whereas a JWT consists of three
+ * dot-separated segments
+ */
+pub fn looks_like_jwt(token: &str) -> bool {
+    token.split('.').count() == 3
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+
+    const TEST_KEY: &[u8] = include_bytes!("../tests/fixtures/jwt-rsa.pem");
+
+    async fn issuer() -> JwtIssuer {
+        JwtIssuer::load(&JwtIssuerConfig {
+            issuer: "https://partner.example.com".to_string(),
+            audience: "riverbank".to_string(),
+            jwks_url: None,
+            jwks_file: Some(PathBuf::from("tests/fixtures/jwks.json")),
+            recipient_claim: "sub".to_string(),
+            recipients: vec!["acme-partner".to_string()],
+            algorithm: Algorithm::RS256,
+        })
+        .await
+        .expect("Failed to load the test issuer")
+    }
+
+    fn sign(claims: Value) -> String {
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some("riverbank-test".to_string());
+        jsonwebtoken::encode(
+            &header,
+            &claims,
+            &EncodingKey::from_rsa_pem(TEST_KEY).unwrap(),
+        )
+        .unwrap()
+    }
+
+    fn claims() -> Value {
+        json!({
+            "iss" : "https://partner.example.com",
+            "aud" : "riverbank",
+            "sub" : "acme-partner",
+            "exp" : chrono::Utc::now().timestamp() + 300,
+        })
+    }
+
+    #[async_std::test]
+    async fn test_valid_token() {
+        let token = sign(claims());
+        let issuers = vec![issuer().await];
+
+        assert!(looks_like_jwt(&token));
+        let issuer = issuer_of(&token, &issuers).expect("Failed to find the issuer");
+        assert_eq!("acme-partner", issuer.recipient(&token).await.unwrap());
+    }
+
+    #[async_std::test]
+    async fn test_invalid_tokens() {
+        let issuer = issuer().await;
+
+        let mut expired = claims();
+        expired["exp"] = json!(chrono::Utc::now().timestamp() - 300);
+        assert!(issuer.recipient(&sign(expired)).await.is_err());
+
+        let mut wrong_audience = claims();
+        wrong_audience["aud"] = json!("someone-else");
+        assert!(issuer.recipient(&sign(wrong_audience)).await.is_err());
+
+        let mut wrong_issuer = claims();
+        wrong_issuer["iss"] = json!("https://evil.example.com");
+        assert!(issuer.recipient(&sign(wrong_issuer)).await.is_err());
+
+        let mut other_recipient = claims();
+        other_recipient["sub"] = json!("someone-elses-recipient");
+        assert!(issuer.recipient(&sign(other_recipient)).await.is_err());
+
+        let mut no_recipient = claims();
+        no_recipient.as_object_mut().unwrap().remove("sub");
+        assert!(issuer.recipient(&sign(no_recipient)).await.is_err());
+    }
+
+    #[async_std::test]
+    async fn test_refresh_cooldown() {
+        let issuer = issuer().await;
+
+        // The key set was just loaded
+        assert!(!issuer.jwks.may_refresh());
+        *issuer.jwks.refreshed_at.lock().unwrap() = Instant::now() - REFRESH_COOLDOWN;
+        assert!(issuer.jwks.may_refresh());
+        assert!(!issuer.jwks.may_refresh());
+    }
+
+    #[test]
+    fn test_looks_like_jwt() {
+        assert!(!looks_like_jwt("d0ec7722-b30c-4e1c-92cd-b4fe8d3bb954"));
+    }
+}
@@ -4,35 +4,140 @@
 
 use log::*;
 use sqlx::PgPool;
+use structopt::StructOpt;
 
+mod cache;
+mod capabilities;
+mod catalog;
+mod cli;
 mod config;
+mod jwt;
+mod logging;
+mod metrics;
 mod models;
+mod oidc;
+mod reload;
 mod routes;
+mod server;
 mod state;
+mod telemetry;
+mod watcher;
+mod webhooks;
 
 use crate::state::AppState;
 
 #[async_std::main]
 async fn main() -> Result<(), tide::Error> {
     dotenv::dotenv().ok();
-    pretty_env_logger::init();
+    let opts = cli::Opts::from_args();
+    let conf = match config::Config::load(&opts.config, |config| opts.overrides.apply(config)) {
+        Ok(conf) => conf,
+        Err(errors) => {
+            eprintln!("{}", errors);
+            std::process::exit(1);
+        }
+    };
 
-    let conf = config::Config::from_file("config.yml").expect("Failed to load configuration");
-    let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    let db = PgPool::connect(&database_url).await?;
-    let state = AppState::new(db, conf);
+    match opts.command {
+        None | Some(cli::Command::Serve) => serve(conf, opts.config, opts.overrides).await,
+        Some(command) => {
+            // Only problems should be logged in between the output of commands
+            let logging = config::LoggingConfig {
+                filter: "warn".to_string(),
+                ..conf.logging.clone()
+            };
+            logging::init(&logging, None);
+            let db = match &conf.database.url {
+                Some(database_url) => Some(PgPool::connect(database_url).await?),
+                None => None,
+            };
+            let catalog = catalog::connect(&conf, db.as_ref()).await?;
+            cli::run(command, &conf, catalog.as_ref(), db.as_ref()).await
+        }
+    }
+}
+
+/**
+ * Serve the sharing API and the admin console until shut down, reloading the
+ * configuration from the given path when it changes
+ */
+async fn serve(
+    conf: config::Config,
+    path: std::path::PathBuf,
+    overrides: cli::Overrides,
+) -> Result<(), tide::Error> {
+    let tracer = match conf.tracing.as_ref().map(telemetry::tracer).transpose() {
+        Ok(tracer) => tracer,
+        Err(e) => {
+            eprintln!("Failed to set up the OpenTelemetry exporter: {}", e);
+            std::process::exit(1);
+        }
+    };
+    logging::init(&conf.logging, tracer);
+    let db = match &conf.database.url {
+        Some(database_url) => Some(PgPool::connect(database_url).await?),
+        None => {
+            info!("Running without a database since DATABASE_URL is not set");
+            None
+        }
+    };
+    if conf.admin.username.is_none() {
+        warn!("The built-in admin user is disabled since RIVERBANK_ADMIN is not set");
+    }
+    let catalog = catalog::connect(&conf, db.as_ref()).await?;
+    info!("Keeping the catalog in {:?}", conf.catalog);
+    let mut state = AppState::new(db, catalog, conf);
+    let config = state.config();
+
+    if let Some(oidc) = &config.oidc {
+        info!("Enabling OpenID Connect login with {}", oidc.issuer);
+        state.oidc = Some(async_std::sync::Arc::new(
+            oidc::OidcClient::discover(oidc).await?,
+        ));
+    }
+
+    let mut issuers = vec![];
+    for issuer in &config.jwt_issuers {
+        info!("Accepting JWT bearer tokens from {}", issuer.issuer);
+        issuers.push(jwt::JwtIssuer::load(issuer).await?);
+    }
+    state.swap(crate::state::Reloadable {
+        jwt_issuers: async_std::sync::Arc::new(issuers),
+        ..state.reloadable()
+    });
 
     state.register_templates().await?;
 
+    /*
+     * The table status and webhook deliveries refer to tables in Postgres, so
+     * both are left out when the catalog is kept elsewhere
+     */
+    match &state.db {
+        Some(db) if config.catalog.is_postgres() => {
+            if config.watcher.interval_seconds > 0 {
+                async_std::task::spawn(watcher::watch(state.clone(), db.clone()));
+            }
+            async_std::task::spawn(webhooks::deliver(state.clone(), db.clone()));
+        }
+        _ => warn!("The table watcher and webhooks need the catalog to be kept in Postgres"),
+    }
+
+    let (signals, reloads) = server::tee(server::signals()?);
+    async_std::task::spawn(reload::watch(state.clone(), path, overrides, reloads));
+
+    let server_config = config.server.clone();
     let mut app = tide::with_state(state);
+    app.with(logging::RequestIdMiddleware {});
+    app.with(metrics::MetricsMiddleware {});
 
+    routes::health::register(&mut app);
     routes::v1::register(&mut app);
     routes::admin::register(&mut app);
 
-    app.at("/")
-        .get(|req: tide::Request<AppState<'static>>| async move {
-            req.state().render("index", None).await
-        });
+    metrics::at(&mut app, "", "/metrics").get(metrics::render);
+    metrics::at(&mut app, "", "/").get(|req: tide::Request<AppState<'static>>| async move {
+        req.state().render("index", None).await
+    });
 
     #[cfg(debug_assertions)]
     {
@@ -50,10 +155,11 @@ async fn main() -> Result<(), tide::Error> {
 
         app.with(cors);
 
+        metrics::register("/apidocs/*");
         app.at("/apidocs").serve_dir("apidocs/")?;
     }
 
-    if let Some(fd) = std::env::var("LISTEN_FD")
+    let listener: async_std::net::TcpListener = if let Some(fd) = std::env::var("LISTEN_FD")
         .ok()
         .and_then(|fd| fd.parse().ok())
     {
@@ -62,12 +168,13 @@ async fn main() -> Result<(), tide::Error> {
          *
          * <https://github.com/passcod/catflap>
          */
-        use std::net::TcpListener;
         use std::os::unix::io::FromRawFd;
-        app.listen(unsafe { TcpListener::from_raw_fd(fd) }).await?;
+        unsafe { std::net::TcpListener::from_raw_fd(fd) }.into()
     } else {
-        app.listen("0.0.0.0:8000").await?;
-    }
+        async_std::net::TcpListener::bind(&server_config.bind).await?
+    };
+    server::serve(app, listener, &server_config, signals).await?;
 
+    telemetry::shutdown();
     Ok(())
 }
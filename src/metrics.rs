@@ -0,0 +1,234 @@
+/*
+ * The metrics module collects Prometheus metrics about the requests handled by
+ * riverbank and the work done for them, which are exposed on `/metrics`
+ */
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram, register_histogram_vec, register_int_counter, register_int_counter_vec,
+    register_int_gauge, Encoder, Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    TextEncoder,
+};
+use std::sync::RwLock;
+use std::time::Instant;
+use tide::{Request, Response};
+
+use crate::state::AppState;
+
+lazy_static! {
+    pub static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "riverbank_http_requests_total",
+        "Number of HTTP requests handled",
+        &["method", "route", "status"]
+    )
+    .unwrap();
+    pub static ref HTTP_DURATION: HistogramVec = register_histogram_vec!(
+        "riverbank_http_request_duration_seconds",
+        "Time taken to handle HTTP requests",
+        &["method", "route"]
+    )
+    .unwrap();
+    pub static ref DELTA_LOAD_DURATION: Histogram = register_histogram!(
+        "riverbank_delta_load_duration_seconds",
+        "Time taken to load the latest version of a Delta table"
+    )
+    .unwrap();
+    pub static ref QUERY_FILES: Histogram = register_histogram!(
+        "riverbank_query_files",
+        "Number of data files served per table query",
+        prometheus::exponential_buckets(1.0, 4.0, 10).unwrap()
+    )
+    .unwrap();
+    pub static ref QUERY_BYTES: Histogram = register_histogram!(
+        "riverbank_query_bytes",
+        "Size of the data files served per table query",
+        prometheus::exponential_buckets(1024.0, 8.0, 10).unwrap()
+    )
+    .unwrap();
+    pub static ref PRESIGN_FAILURES: IntCounter = register_int_counter!(
+        "riverbank_presign_failures_total",
+        "Number of data files which could not be presigned"
+    )
+    .unwrap();
+    pub static ref TOKEN_AUTH_FAILURES: IntCounter = register_int_counter!(
+        "riverbank_token_auth_failures_total",
+        "Number of sharing API requests without a valid bearer token"
+    )
+    .unwrap();
+    static ref DB_POOL_CONNECTIONS: IntGauge = register_int_gauge!(
+        "riverbank_db_pool_connections",
+        "Number of open database connections"
+    )
+    .unwrap();
+    static ref DB_POOL_IDLE: IntGauge = register_int_gauge!(
+        "riverbank_db_pool_idle_connections",
+        "Number of open database connections which are not in use"
+    )
+    .unwrap();
+    pub static ref TABLE_CACHE: IntCounterVec = register_int_counter_vec!(
+        "riverbank_table_cache_total",
+        "Lookups in the Delta table cache by result",
+        &["result"]
+    )
+    .unwrap();
+    static ref TABLE_CACHE_ENTRIES: IntGauge = register_int_gauge!(
+        "riverbank_table_cache_entries",
+        "Number of Delta tables in the cache"
+    )
+    .unwrap();
+    /// The patterns of the routes registered on the server, which are the
+    /// only values of the `route` label besides "unmatched"
+    static ref ROUTES: RwLock<Vec<&'static str>> = RwLock::new(vec![]);
+}
+
+/**
+ * Record the pattern of a route, where a final `*` segment matches the rest of
+ * the path
+ */
+pub fn register(pattern: &str) {
+    let pattern = match pattern.trim_end_matches('/') {
+        "" => "/",
+        trimmed => trimmed,
+    };
+    let mut routes = ROUTES.write().unwrap();
+    if !routes.contains(&pattern) {
+        // Routes are only registered while the server is set up
+        routes.push(Box::leak(pattern.to_string().into_boxed_str()));
+    }
+}
+
+/**
+ * Add a route to the server, which is nested at the prefix, recording its full
+ * pattern for the `route` label
+ */
+pub fn at<'a, State: Clone + Send + Sync + 'static>(
+    server: &'a mut tide::Server<State>,
+    prefix: &str,
+    path: &str,
+) -> tide::Route<'a, State> {
+    register(&format!("{}{}", prefix, path));
+    server.at(path)
+}
+
+/**
+ * Count and time every request by its route
+ */
+#[derive(Default)]
+pub struct MetricsMiddleware;
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> tide::Middleware<State> for MetricsMiddleware {
+    async fn handle(&self, req: Request<State>, next: tide::Next<'_, State>) -> tide::Result {
+        let started = Instant::now();
+        let method = req.method().to_string();
+        let route = route_label(req.url().path());
+
+        let response = next.run(req).await;
+
+        HTTP_DURATION
+            .with_label_values(&[method.as_str(), route])
+            .observe(started.elapsed().as_secs_f64());
+        HTTP_REQUESTS
+            .with_label_values(&[method.as_str(), route, &response.status().to_string()])
+            .inc();
+        Ok(response)
+    }
+}
+
+/**
+ * The registered route which the path of a request matches, so that every
+ * route has a single label no matter which share or table is requested.
+ *
+ * Any other path is "unmatched", whatever the response to it, since those
+ * would otherwise each get a label of their own
+ */
+pub fn route_label(path: &str) -> &'static str {
+    let path = match path.trim_end_matches('/') {
+        "" => "/",
+        trimmed => trimmed,
+    };
+    let segments: Vec<&str> = path.split('/').collect();
+    ROUTES
+        .read()
+        .unwrap()
+        .iter()
+        .find(|route| {
+            let pattern: Vec<&str> = route.split('/').collect();
+            let matches = |(p, s): (&&str, &&str)| {
+                if p.starts_with(':') {
+                    !s.is_empty()
+                } else {
+                    p == s
+                }
+            };
+            match pattern.split_last() {
+                Some((&"*", prefix)) => {
+                    segments.len() > prefix.len() && prefix.iter().zip(segments.iter()).all(matches)
+                }
+                _ => {
+                    pattern.len() == segments.len()
+                        && pattern.iter().zip(segments.iter()).all(matches)
+                }
+            }
+        })
+        .copied()
+        .unwrap_or("unmatched")
+}
+
+/**
+ * GET /metrics
+ *
+ * Render all metrics in the Prometheus text format
+ */
+pub async fn render(req: Request<AppState<'_>>) -> tide::Result {
+    if let Some(db) = &req.state().db {
+        DB_POOL_CONNECTIONS.set(db.size() as i64);
+        DB_POOL_IDLE.set(db.num_idle() as i64);
+    }
+
+    TABLE_CACHE_ENTRIES.set(req.state().table_cache.stats().entries as i64);
+
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    encoder.encode(&prometheus::gather(), &mut buffer)?;
+
+    Ok(Response::builder(200)
+        .header("Content-Type", encoder.format_type())
+        .body(buffer)
+        .build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route_label() {
+        let mut app = tide::new();
+        at(&mut app, "", "/");
+        at(&mut app, "/api/v1", "/shares");
+        at(
+            &mut app,
+            "/api/v1",
+            "/shares/:share/schemas/:schema/tables/:table/query",
+        );
+        at(&mut app, "/admin", "/");
+        at(&mut app, "/admin", "/tokens/share/:id");
+        register("/apidocs/*");
+
+        assert_eq!("/api/v1/shares", route_label("/api/v1/shares"));
+        assert_eq!(
+            "/api/v1/shares/:share/schemas/:schema/tables/:table/query",
+            route_label("/api/v1/shares/vaccine_share/schemas/acme_vaccine_data/tables/vaccine_ingredients/query")
+        );
+        assert_eq!(
+            "/admin/tokens/share/:id",
+            route_label("/admin/tokens/share/64947f1f-ef50-4280-bf6b-160c075cde43")
+        );
+        assert_eq!("/apidocs/*", route_label("/apidocs/index.html"));
+        assert_eq!("/", route_label("/"));
+        assert_eq!("/admin", route_label("/admin/"));
+        assert_eq!("unmatched", route_label("/api/v1/unknown/share"));
+        assert_eq!("unmatched", route_label("/admin/../etc/passwd"));
+        assert_eq!("unmatched", route_label("/api/v1/shares/a/b/c/d/e/f/g/h"));
+    }
+}
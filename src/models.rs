@@ -1,12 +1,41 @@
+use async_std::sync::Arc;
 use chrono::{DateTime, Utc};
-use deltalake::{DeltaTable, DeltaTableError, DeltaTableMetaData};
+use deltalake::{DeltaTableError, DeltaTableMetaData};
+use lazy_static::lazy_static;
 use log::*;
+use regex::Regex;
 use serde::Serialize;
-use sqlx::PgPool;
+use sqlx::{PgConnection, PgPool};
 use std::collections::HashMap;
+use tracing::instrument;
 use uuid::Uuid;
 
-#[derive(Clone, Debug, Serialize)]
+use crate::cache::{Snapshot, TableCache};
+use crate::config::StorageConfig;
+use crate::logging::REDACTED;
+use crate::metrics;
+use crate::state::Tokened;
+
+/// The migrations in `./migrations`, embedded at build time
+pub static MIGRATOR: sqlx::migrate::Migrator = sqlx::migrate!("./migrations");
+
+/**
+ * Return the versions of the embedded migrations which have not been
+ * successfully applied to the database
+ */
+#[instrument(name = "pending_migrations", skip(db))]
+pub async fn pending_migrations(db: &PgPool) -> Result<Vec<i64>, sqlx::Error> {
+    let applied = sqlx::query_scalar!("SELECT version FROM _sqlx_migrations WHERE success")
+        .fetch_all(db)
+        .await?;
+    Ok(MIGRATOR
+        .iter()
+        .map(|migration| migration.version)
+        .filter(|version| !applied.contains(version))
+        .collect())
+}
+
+#[derive(Clone, Debug, Serialize, sqlx::FromRow)]
 pub struct Share {
     pub id: Uuid,
     pub name: String,
@@ -14,6 +43,7 @@ pub struct Share {
 }
 
 impl Share {
+    #[instrument(name = "Share::list_all", skip(db))]
     pub async fn list_all(db: &PgPool) -> Result<Vec<Share>, sqlx::Error> {
         sqlx::query_as!(Share, r#"SELECT * FROM shares ORDER BY created_at ASC"#)
             .fetch_all(db)
@@ -22,16 +52,18 @@ impl Share {
     /**
      * This function will return all the Shares that are visible to the given token
      */
+    #[instrument(name = "Share::list_by_token", skip(db))]
     pub async fn list_by_token(token_id: &Uuid, db: &PgPool) -> Result<Vec<Share>, sqlx::Error> {
         sqlx::query_as!(
             Share,
             r#"
-            SELECT shares.* FROM shares, schemas
+            SELECT DISTINCT shares.* FROM shares, schemas
                 WHERE shares.id = schemas.share_id
                 AND schemas.id IN
                     (SELECT schema_id FROM tables, tokens_for_tables
                         WHERE tables.id = tokens_for_tables.table_id
                         AND tokens_for_tables.token_id = $1)
+                ORDER BY shares.name, shares.id
             "#,
             token_id
         )
@@ -39,25 +71,61 @@ impl Share {
         .await
     }
 
-    pub async fn by_id(id: &Uuid, db: &PgPool) -> Result<Share, sqlx::Error> {
+    /**
+     * Find the named share, if at least one of its tables is granted to the
+     * given token
+     */
+    #[instrument(name = "Share::find_by_token", skip(db))]
+    pub async fn find_by_token(
+        name: &str,
+        token_id: &Uuid,
+        db: &PgPool,
+    ) -> Result<Share, sqlx::Error> {
+        sqlx::query_as!(
+            Share,
+            r#"
+            SELECT shares.* FROM shares
+                WHERE shares.name = $1
+                AND EXISTS
+                    (SELECT 1 FROM schemas, tables, tokens_for_tables
+                        WHERE schemas.share_id = shares.id
+                        AND tables.schema_id = schemas.id
+                        AND tables.id = tokens_for_tables.table_id
+                        AND tokens_for_tables.token_id = $2)
+            "#,
+            name,
+            token_id
+        )
+        .fetch_one(db)
+        .await
+    }
+
+    #[instrument(name = "Share::by_id", skip(db))]
+    pub async fn by_id<'e, E>(id: &Uuid, db: E) -> Result<Share, sqlx::Error>
+    where
+        E: sqlx::Executor<'e, Database = sqlx::Postgres>,
+    {
         sqlx::query_as!(Share, r#"SELECT * FROM shares WHERE id = $1"#, id)
             .fetch_one(db)
             .await
     }
 
-    pub async fn create(name: &str, db: &PgPool) -> Result<Share, sqlx::Error> {
-        let record = sqlx::query!(
-            r#"INSERT INTO shares (name)
-                VALUES ($1) RETURNING id"#,
+    #[instrument(name = "Share::create", skip(conn))]
+    pub async fn create(name: &str, conn: &mut PgConnection) -> Result<Share, sqlx::Error> {
+        let id = Uuid::new_v4();
+        sqlx::query!(
+            r#"INSERT INTO shares (id, name)
+                VALUES ($1, $2)"#,
+            id,
             name
         )
-        .fetch_one(db)
+        .execute(&mut *conn)
         .await?;
-        Share::by_id(&record.id, db).await
+        Share::by_id(&id, conn).await
     }
 }
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Serialize, sqlx::FromRow)]
 pub struct Schema {
     pub id: Uuid,
     pub name: String,
@@ -67,6 +135,7 @@ pub struct Schema {
 }
 
 impl Schema {
+    #[instrument(name = "Schema::list_by_token", skip(db))]
     pub async fn list_by_token(
         share: &str,
         token_id: &Uuid,
@@ -81,7 +150,7 @@ impl Schema {
                     (SELECT schema_id FROM tables, tokens_for_tables
                         WHERE tables.id = tokens_for_tables.table_id
                         AND tokens_for_tables.token_id = $2)
-                ORDER BY share_id ASC
+                ORDER BY schemas.name, schemas.id
                 "#,
             share,
             token_id
@@ -90,6 +159,7 @@ impl Schema {
         .await
     }
 
+    #[instrument(name = "Schema::list_all", skip(db))]
     pub async fn list_all(db: &PgPool) -> Result<Vec<Schema>, sqlx::Error> {
         use chrono::prelude::*;
         // Binding the created_at parameter to psyche out sqlx inference, see:
@@ -101,6 +171,7 @@ impl Schema {
             .await
     }
 
+    #[instrument(name = "Schema::find", skip(db))]
     pub async fn find(share: &str, schema: &str, db: &PgPool) -> Result<Schema, sqlx::Error> {
         sqlx::query_as!(
             Schema,
@@ -115,7 +186,11 @@ impl Schema {
         .await
     }
 
-    pub async fn by_id(id: &Uuid, db: &PgPool) -> Result<Schema, sqlx::Error> {
+    #[instrument(name = "Schema::by_id", skip(db))]
+    pub async fn by_id<'e, E>(id: &Uuid, db: E) -> Result<Schema, sqlx::Error>
+    where
+        E: sqlx::Executor<'e, Database = sqlx::Postgres>,
+    {
         sqlx::query_as!(
             Schema,
             r#"SELECT schemas.*, shares.name AS share_name FROM schemas, shares
@@ -127,19 +202,26 @@ impl Schema {
         .await
     }
 
-    pub async fn create(name: &str, share_id: &Uuid, db: &PgPool) -> Result<Schema, sqlx::Error> {
+    #[instrument(name = "Schema::create", skip(conn))]
+    pub async fn create(
+        name: &str,
+        share_id: &Uuid,
+        conn: &mut PgConnection,
+    ) -> Result<Schema, sqlx::Error> {
         // Just querying for the share to validate the presence of the record
-        let _share = Share::by_id(share_id, db).await?;
+        let _share = Share::by_id(share_id, &mut *conn).await?;
 
-        let record = sqlx::query!(
-            r#"INSERT INTO schemas (name, share_id)
-                VALUES ($1, $2) RETURNING id"#,
+        let id = Uuid::new_v4();
+        sqlx::query!(
+            r#"INSERT INTO schemas (id, name, share_id)
+                VALUES ($1, $2, $3)"#,
+            id,
             name,
             share_id
         )
-        .fetch_one(db)
+        .execute(&mut *conn)
         .await?;
-        Schema::by_id(&record.id, db).await
+        Schema::by_id(&id, conn).await
     }
 }
 
@@ -153,10 +235,19 @@ pub struct Table {
     pub inner: PrimitiveTable,
     schema: Schema,
     #[serde(skip_serializing)]
-    delta_table: Option<DeltaTable>,
+    delta_table: Option<Arc<Snapshot>>,
 }
 
 impl Table {
+    /// Combine a table with the schema it belongs to
+    pub fn new(inner: PrimitiveTable, schema: Schema) -> Table {
+        Table {
+            inner,
+            schema,
+            delta_table: None,
+        }
+    }
+
     /// Return the name of the table
     pub fn name(&self) -> &str {
         &self.inner.name
@@ -172,79 +263,124 @@ impl Table {
         &self.schema.share_name
     }
 
-    pub async fn load_delta(&mut self) -> Result<(), DeltaTableError> {
-        self.delta_table = Some(deltalake::open_table(&self.inner.location).await?);
+    /// Return the id of the table, which stays the same as long as it is shared
+    pub fn id(&self) -> &Uuid {
+        &self.inner.id
+    }
+
+    /// Return the id of the share the table is associated with
+    pub fn share_id(&self) -> &Uuid {
+        &self.schema.share_id
+    }
+
+    /**
+     * Load the latest version of the Delta table, from the cache when it has
+     * been loaded recently
+     */
+    #[instrument(name = "Table::load_delta", skip(self, cache), fields(location = %self.inner.location))]
+    pub async fn load_delta(&mut self, cache: &TableCache) -> Result<(), DeltaTableError> {
+        let _timer = metrics::DELTA_LOAD_DURATION.start_timer();
+        self.delta_table = Some(cache.load(&self.inner.location).await?);
         Ok(())
     }
 
-    pub fn delta_version(&mut self) -> Result<String, DeltaTableError> {
+    pub fn delta_version(&mut self) -> Result<i64, DeltaTableError> {
         if let Some(delta) = &self.delta_table {
-            return Ok(delta.version.to_string());
+            return Ok(delta.version);
         }
         Err(DeltaTableError::NotATable)
     }
 
+    /**
+     * The protocol of the loaded version. Tables which require reader
+     * features are refused in the parquet format, so it never lists any
+     */
     pub fn protocol(&mut self) -> Result<Protocol, DeltaTableError> {
         if let Some(delta) = &self.delta_table {
             return Ok(Protocol {
-                min_reader_version: delta.get_min_reader_version(),
+                min_reader_version: delta.min_reader_version,
             });
         }
         Err(DeltaTableError::NotATable)
     }
 
-    pub fn metadata(&mut self) -> Result<Metadata, DeltaTableError> {
+    /**
+     * The protocol for `responseFormat=delta`, which is the protocol action
+     * of the Delta log. Reader features only exist from reader version 3 on
+     */
+    pub fn delta_protocol(&mut self) -> Result<DeltaProtocol, DeltaTableError> {
         if let Some(delta) = &self.delta_table {
-            return Ok(Metadata::from_metadata(delta.get_metadata()?));
+            return Ok(DeltaProtocol {
+                delta_protocol: DeltaProtocolAction {
+                    min_reader_version: delta.min_reader_version,
+                    min_writer_version: delta.min_writer_version,
+                    reader_features: Some(delta.reader_features.clone())
+                        .filter(|_| delta.min_reader_version >= 3),
+                },
+            });
         }
         Err(DeltaTableError::NotATable)
     }
 
-    pub async fn urls(&mut self) -> Result<Vec<serde_json::Value>, DeltaTableError> {
-        use rusoto_core::Region;
-        use rusoto_credential::ChainProvider;
-        use rusoto_credential::ProvideAwsCredentials;
-        use rusoto_s3::util::PreSignedRequest;
-        use rusoto_s3::GetObjectRequest;
-        use serde_json::json;
+    /**
+     * The metadata for `responseFormat=delta`, which wraps the metaData
+     * action of the Delta log as it is along with the version and its size
+     */
+    pub fn delta_metadata(&mut self) -> Result<DeltaMetadata, DeltaTableError> {
+        if let Some(delta) = &self.delta_table {
+            return Ok(DeltaMetadata {
+                delta_metadata: DeltaMetadataAction::from_metadata(&delta.metadata)?,
+                version: delta.version,
+                num_files: delta.num_files,
+                size: delta.size,
+            });
+        }
+        Err(DeltaTableError::NotATable)
+    }
 
+    /// The reader features required to read the loaded version
+    pub fn reader_features(&self) -> Result<&[String], DeltaTableError> {
         match &self.delta_table {
+            Some(delta) => Ok(&delta.reader_features),
             None => Err(DeltaTableError::NotATable),
+        }
+    }
+
+    /**
+     * The metadata of the loaded version, optionally with the number and
+     * total size of its data files
+     */
+    pub fn metadata(&mut self, include_stats: bool) -> Result<Metadata, DeltaTableError> {
+        if let Some(delta) = &self.delta_table {
+            let mut metadata = Metadata::from_metadata(&self.inner, &delta.metadata)?;
+            if include_stats {
+                metadata.num_files = Some(delta.num_files);
+                metadata.size = Some(delta.size);
+            }
+            return Ok(metadata);
+        }
+        Err(DeltaTableError::NotATable)
+    }
+
+    #[instrument(name = "Table::urls", skip(self, storage), fields(location = %self.inner.location))]
+    pub async fn urls(
+        &mut self,
+        storage: &StorageConfig,
+    ) -> Result<Vec<serde_json::Value>, PresignError> {
+        use serde_json::json;
+
+        match &self.delta_table {
+            None => Err(DeltaTableError::NotATable.into()),
             Some(delta) => {
+                let presigner = Presigner::new(storage).await?;
                 let mut urls = vec![];
 
-                let region = if let Ok(url) = std::env::var("AWS_ENDPOINT_URL") {
-                    Region::Custom {
-                        name: std::env::var("AWS_REGION").unwrap_or_else(|_| "custom".to_string()),
-                        endpoint: url,
-                    }
-                } else {
-                    Region::default()
-                };
-                let options = rusoto_s3::util::PreSignedRequestOption {
-                    // TODO: make this configurable
-                    expires_in: std::time::Duration::from_secs(300),
-                };
-                let provider = ChainProvider::new();
-                // TODO map the error
-                let credentials = provider
-                    .credentials()
-                    .await
-                    .expect("Failed to get credentials");
-
-                for add in delta.get_actions() {
+                for add in delta.files.iter() {
+                    let _span = tracing::info_span!("presign", path = %add.path).entered();
                     let file = format!("{}/{}", delta.table_uri, &add.path);
-                    let s3obj = deltalake::storage::parse_uri(&file)?.into_s3object()?;
-                    let req = GetObjectRequest {
-                        bucket: s3obj.bucket.to_string(),
-                        key: s3obj.key.to_string(),
-                        ..Default::default()
-                    };
-                    debug!("get request: {:?}", req);
-                    let url = req.get_presigned_url(&region, &credentials, &options);
                     urls.push(json!({
                         "file" : {
-                            "url" : url,
+                            "url" : presigner.presign(&file)?,
                             "id" : id_from_file(&file),
                             "partitionValues" : add.partition_values,
                             "size" : add.size,
@@ -252,14 +388,51 @@ impl Table {
                         }
                     }));
                 }
+                metrics::QUERY_FILES.observe(urls.len() as f64);
+                metrics::QUERY_BYTES.observe(delta.size as f64);
                 Ok(urls)
             }
         }
     }
 
+    /**
+     * The add actions of the loaded version as they are in the Delta log,
+     * for `responseFormat=delta`, with the presigned URLs of the data files
+     * in place of their paths
+     */
+    #[instrument(name = "Table::actions", skip(self, storage), fields(location = %self.inner.location))]
+    pub async fn actions(
+        &mut self,
+        storage: &StorageConfig,
+    ) -> Result<Vec<serde_json::Value>, PresignError> {
+        match &self.delta_table {
+            None => Err(DeltaTableError::NotATable.into()),
+            Some(delta) => {
+                let presigner = Presigner::new(storage).await?;
+                let expires_in = chrono::Duration::from_std(PRESIGN_EXPIRY).unwrap();
+                let expiration = (Utc::now() + expires_in).timestamp_millis();
+                let mut actions = vec![];
+
+                for add in delta.files.iter() {
+                    let _span = tracing::info_span!("presign", path = %add.path).entered();
+                    actions.push(add_action(
+                        &delta.table_uri,
+                        serde_json::to_value(add)?,
+                        expiration,
+                        |file| presigner.presign(file),
+                    )?);
+                }
+                metrics::QUERY_FILES.observe(actions.len() as f64);
+                metrics::QUERY_BYTES.observe(delta.size as f64);
+                Ok(actions)
+            }
+        }
+    }
+
     /**
      * List all the tables that exist in the database
      */
+    #[instrument(name = "Table::list_all", skip(db))]
     pub async fn list_all(db: &PgPool) -> Result<Vec<Table>, sqlx::Error> {
         let pts = sqlx::query_as!(PrimitiveTable, "SELECT * FROM tables ORDER BY created_at")
             .fetch_all(db)
@@ -290,6 +463,7 @@ impl Table {
     /**
      * List the tables specifically in the given share and schema
      */
+    #[instrument(name = "Table::list_by_token", skip(db))]
     pub async fn list_by_token(
         share: &str,
         schema: &str,
@@ -304,7 +478,8 @@ impl Table {
                 SELECT tables.* FROM tables, tokens_for_tables
                 WHERE schema_id = $1
                 AND tables.id = tokens_for_tables.table_id
-                AND tokens_for_tables.token_id = $2"#,
+                AND tokens_for_tables.token_id = $2
+                ORDER BY tables.name, tables.id"#,
             schema.id,
             token_id
         )
@@ -321,6 +496,52 @@ impl Table {
             .collect())
     }
 
+    /**
+     * List the tables in every schema of the given share which are granted
+     * to the token
+     */
+    #[instrument(name = "Table::list_by_share", skip(db))]
+    pub async fn list_by_share(
+        share: &str,
+        token_id: &Uuid,
+        db: &PgPool,
+    ) -> Result<Vec<Table>, sqlx::Error> {
+        let share = Share::find_by_token(share, token_id, db).await?;
+        let mut schemas = HashMap::new();
+        for schema in Schema::list_all(db).await? {
+            if schema.share_id == share.id {
+                schemas.insert(schema.id, schema);
+            }
+        }
+
+        let tables = sqlx::query_as!(
+            PrimitiveTable,
+            r#"
+                SELECT tables.* FROM tables, schemas, tokens_for_tables
+                WHERE tables.schema_id = schemas.id
+                AND schemas.share_id = $1
+                AND tables.id = tokens_for_tables.table_id
+                AND tokens_for_tables.token_id = $2
+                ORDER BY schemas.name, tables.name, tables.id"#,
+            share.id,
+            token_id
+        )
+        .fetch_all(db)
+        .await?;
+
+        tables
+            .into_iter()
+            .map(|inner| {
+                let schema = schemas
+                    .get(&inner.schema_id)
+                    .ok_or(sqlx::Error::RowNotFound)?
+                    .clone();
+                Ok(Table::new(inner, schema))
+            })
+            .collect()
+    }
+
+    #[instrument(name = "Table::find", skip(db))]
     pub async fn find(
         share: &str,
         schema: &str,
@@ -353,22 +574,67 @@ impl Table {
         })
     }
 
+    /**
+     * The description set for the table, locking its row until the end of
+     * the transaction so that it can be changed knowing what it was
+     */
+    #[instrument(name = "Table::description_for_update", skip(db))]
+    pub async fn description_for_update<'e, E>(
+        id: &Uuid,
+        db: E,
+    ) -> Result<Option<String>, sqlx::Error>
+    where
+        E: sqlx::Executor<'e, Database = sqlx::Postgres>,
+    {
+        let row = sqlx::query!(
+            r#"SELECT description FROM tables WHERE id = $1 FOR UPDATE"#,
+            id
+        )
+        .fetch_one(db)
+        .await?;
+        Ok(row.description)
+    }
+
+    /**
+     * Set the description handed to recipients in place of the one in the
+     * Delta table, or clear it with None
+     */
+    #[instrument(name = "Table::describe", skip(conn))]
+    pub async fn describe(
+        id: &Uuid,
+        description: Option<&str>,
+        conn: &mut PgConnection,
+    ) -> Result<Table, sqlx::Error> {
+        let inner = sqlx::query_as!(
+            PrimitiveTable,
+            r#"UPDATE tables SET description = $2 WHERE id = $1 RETURNING *"#,
+            id,
+            description
+        )
+        .fetch_one(&mut *conn)
+        .await?;
+        let schema = Schema::by_id(&inner.schema_id, conn).await?;
+        Ok(Table::new(inner, schema))
+    }
+
+    #[instrument(name = "Table::create", skip(conn))]
     pub async fn create(
         name: &str,
         location: &str,
         schema_id: &Uuid,
-        db: &PgPool,
+        conn: &mut PgConnection,
     ) -> Result<Table, sqlx::Error> {
-        let schema = Schema::by_id(schema_id, db).await?;
+        let schema = Schema::by_id(schema_id, &mut *conn).await?;
         let inner = sqlx::query_as!(
             PrimitiveTable,
-            r#"INSERT INTO tables (name, location, schema_id)
-                VALUES ($1, $2, $3) RETURNING *"#,
+            r#"INSERT INTO tables (id, name, location, schema_id)
+                VALUES ($1, $2, $3, $4) RETURNING *"#,
+            Uuid::new_v4(),
             name,
             location,
             schema_id
         )
-        .fetch_one(db)
+        .fetch_one(conn)
         .await?;
         Ok(Table {
             inner,
@@ -378,13 +644,15 @@ impl Table {
     }
 }
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Serialize, sqlx::FromRow)]
 pub struct PrimitiveTable {
     pub id: Uuid,
     pub name: String,
     pub location: String,
-    schema_id: Uuid,
-    created_at: DateTime<Utc>,
+    pub schema_id: Uuid,
+    pub created_at: DateTime<Utc>,
+    /// Handed to recipients in place of the description of the Delta table
+    pub description: Option<String>,
 }
 
 #[derive(Clone, Debug, Serialize)]
@@ -395,35 +663,140 @@ pub struct Protocol {
 
 #[derive(Clone, Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
-pub struct Metadata {
+pub struct DeltaProtocol {
+    delta_protocol: DeltaProtocolAction,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DeltaProtocolAction {
+    min_reader_version: i32,
+    min_writer_version: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reader_features: Option<Vec<String>>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeltaMetadata {
+    delta_metadata: DeltaMetadataAction,
+    version: i64,
+    num_files: i64,
+    size: i64,
+}
+
+/**
+ * The metaData action of the Delta log, with the table's own id, description
+ * and configuration unlike the Metadata of the parquet format
+ */
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DeltaMetadataAction {
     id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
     format: deltalake::action::Format,
     schema_string: String,
     partition_columns: Vec<String>,
+    configuration: HashMap<String, Option<String>>,
+    created_time: i64,
 }
 
-impl Metadata {
-    fn from_metadata(metadata: &DeltaTableMetaData) -> Self {
-        Self {
+impl DeltaMetadataAction {
+    fn from_metadata(metadata: &DeltaTableMetaData) -> Result<Self, DeltaTableError> {
+        Ok(Self {
             id: metadata.id.clone(),
+            name: metadata.name.clone(),
+            description: metadata.description.clone(),
             format: metadata.format.clone(),
-            schema_string: serde_json::to_string(&metadata.schema)
-                .unwrap_or_else(|_| "".to_string()),
+            schema_string: serde_json::to_string(&metadata.schema)?,
             partition_columns: metadata.partition_columns.clone(),
-        }
+            configuration: metadata.configuration.clone(),
+            created_time: metadata.created_time,
+        })
     }
 }
 
 #[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Metadata {
+    id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    format: deltalake::action::Format,
+    schema_string: String,
+    partition_columns: Vec<String>,
+    configuration: HashMap<String, String>,
+    created_time: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    num_files: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<i64>,
+}
+
+impl Metadata {
+    /**
+     * The metadata of the Delta table, identified by the id of the shared
+     * table so that it matches the id in the listings.
+     *
+     * A schema which cannot be serialized is an error, since recipients
+     * cannot read the table without one
+     */
+    fn from_metadata(
+        table: &PrimitiveTable,
+        metadata: &DeltaTableMetaData,
+    ) -> Result<Self, DeltaTableError> {
+        Ok(Self {
+            id: table.id.to_string(),
+            name: metadata.name.clone(),
+            description: table
+                .description
+                .clone()
+                .or_else(|| metadata.description.clone()),
+            format: metadata.format.clone(),
+            schema_string: serde_json::to_string(&metadata.schema)?,
+            partition_columns: metadata.partition_columns.clone(),
+            // Settings without a value are left out, the protocol only has
+            // room for strings
+            configuration: metadata
+                .configuration
+                .iter()
+                .filter_map(|(key, value)| value.clone().map(|value| (key.clone(), value)))
+                .collect(),
+            created_time: metadata.created_time,
+            num_files: None,
+            size: None,
+        })
+    }
+}
+
+#[derive(Clone, Serialize, sqlx::FromRow)]
 pub struct Token {
-    id: Uuid,
+    pub id: Uuid,
     pub name: String,
     pub token: String,
     pub expires_at: DateTime<Utc>,
-    created_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl std::fmt::Debug for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Token")
+            .field("id", &self.id)
+            .field("name", &self.name)
+            .field("token", &REDACTED)
+            .field("expires_at", &self.expires_at)
+            .field("created_at", &self.created_at)
+            .finish()
+    }
 }
 
 impl Token {
+    #[instrument(name = "Token::list_all", skip(db))]
     pub async fn list_all(db: &PgPool) -> Result<Vec<Token>, sqlx::Error> {
         let tokens = sqlx::query_as!(
             Token,
@@ -433,23 +806,34 @@ impl Token {
         .fetch_all(db)
         .await?;
 
-        debug!("tokens from list_all: {:?}", tokens);
         Ok(tokens)
     }
 
-    pub async fn by_id(id: &Uuid, db: &PgPool) -> Result<Token, sqlx::Error> {
+    #[instrument(name = "Token::by_id", skip(db))]
+    pub async fn by_id<'e, E>(id: &Uuid, db: E) -> Result<Token, sqlx::Error>
+    where
+        E: sqlx::Executor<'e, Database = sqlx::Postgres>,
+    {
         sqlx::query_as!(Token, r#"SELECT * FROM tokens WHERE id = $1"#, id)
             .fetch_one(db)
             .await
     }
 
-    pub async fn generate(name: &str, tables: &[Uuid], db: &PgPool) -> Result<Token, sqlx::Error> {
-        let mut tx = db.begin().await?;
+    /**
+     * Create a token with a new secret granting the tables, which should be
+     * done in a transaction so that no token is left without its grants
+     */
+    #[instrument(name = "Token::generate", skip(conn))]
+    pub async fn generate(
+        name: &str,
+        tables: &[Uuid],
+        conn: &mut PgConnection,
+    ) -> Result<Token, sqlx::Error> {
         let secret = Uuid::new_v4();
         let token = sqlx::query_as!(Token,
-            r#"INSERT INTO tokens (name, token, expires_at) VALUES ($1, $2, (NOW() + interval '30 days')) RETURNING *"#,
-            name, secret.to_hyphenated().to_string())
-            .fetch_one(&mut tx)
+            r#"INSERT INTO tokens (id, name, token, expires_at) VALUES ($1, $2, $3, (NOW() + interval '30 days')) RETURNING *"#,
+            Uuid::new_v4(), name, secret.to_hyphenated().to_string())
+            .fetch_one(&mut *conn)
             .await?;
 
         for table in tables {
@@ -458,21 +842,836 @@ impl Token {
                 &token.id,
                 &table
             )
-            .execute(&mut tx)
+            .execute(&mut *conn)
             .await?;
         }
-        tx.commit().await?;
         Ok(token)
     }
+
+    /**
+     * Find the unexpired token with the given secret
+     */
+    #[instrument(name = "Token::authenticate", skip(secret, db))]
+    pub async fn authenticate(secret: &str, db: &PgPool) -> Result<Option<Tokened>, sqlx::Error> {
+        sqlx::query_as!(
+            Tokened,
+            r#"SELECT id, name, token FROM tokens WHERE token = $1 AND expires_at > NOW()"#,
+            secret
+        )
+        .fetch_optional(db)
+        .await
+    }
+
+    /**
+     * Find the most recent unexpired token of the recipient, whose grants
+     * apply to the recipient's JWTs
+     */
+    #[instrument(name = "Token::for_recipient", skip(db))]
+    pub async fn for_recipient(
+        recipient: &str,
+        db: &PgPool,
+    ) -> Result<Option<Tokened>, sqlx::Error> {
+        sqlx::query_as!(
+            Tokened,
+            r#"SELECT id, name, token FROM tokens
+                WHERE name = $1 AND expires_at > NOW()
+                ORDER BY created_at DESC LIMIT 1"#,
+            recipient
+        )
+        .fetch_optional(db)
+        .await
+    }
+
+    /**
+     * Revoke the token by expiring it right away, which keeps it around for
+     * the access log and audit trail
+     */
+    #[instrument(name = "Token::revoke", skip(db))]
+    pub async fn revoke<'e, E>(id: &Uuid, db: E) -> Result<Token, sqlx::Error>
+    where
+        E: sqlx::Executor<'e, Database = sqlx::Postgres>,
+    {
+        sqlx::query_as!(
+            Token,
+            r#"UPDATE tokens SET expires_at = NOW()
+                WHERE id = $1 AND expires_at > NOW() RETURNING *"#,
+            id
+        )
+        .fetch_one(db)
+        .await
+    }
+
+    /**
+     * The Delta Sharing profile file which recipients use to connect with the
+     * token
+     */
+    pub fn profile(&self, endpoint: &str) -> serde_json::Value {
+        serde_json::json!({
+            "shareCredentialsVersion" : 1,
+            "bearerToken" : self.token,
+            "endpoint" : endpoint,
+            "expirationTime" : self.expires_at.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        })
+    }
 }
 
-fn id_from_file(file: &str) -> Option<&str> {
-    use regex::Regex;
+/**
+ * A new entry for the access_log, describing a single request made by a
+ * recipient to the sharing API
+ */
+#[derive(Clone, Debug)]
+pub struct AccessEntry {
+    pub token_id: Uuid,
+    pub operation: &'static str,
+    pub share_name: Option<String>,
+    pub schema_name: Option<String>,
+    pub table_name: Option<String>,
+    pub table_version: Option<i64>,
+    pub predicate_hints: Option<serde_json::Value>,
+    pub num_files: Option<i64>,
+    pub num_bytes: Option<i64>,
+    pub client_ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+/**
+ * The criteria for searching the access_log, where any unset field matches
+ * every entry
+ */
+#[derive(Clone, Debug, Default)]
+pub struct AccessFilter {
+    pub token_name: Option<String>,
+    pub share_name: Option<String>,
+    pub table_name: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub limit: i64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct AccessLog {
+    pub id: Uuid,
+    pub token_id: Uuid,
+    pub token_name: String,
+    pub operation: String,
+    pub share_name: Option<String>,
+    pub schema_name: Option<String>,
+    pub table_name: Option<String>,
+    pub table_version: Option<i64>,
+    pub predicate_hints: Option<serde_json::Value>,
+    pub num_files: Option<i64>,
+    pub num_bytes: Option<i64>,
+    pub client_ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl AccessLog {
+    #[instrument(name = "AccessLog::record", skip(db))]
+    pub async fn record(entry: &AccessEntry, db: &PgPool) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"INSERT INTO access_log
+                (token_id, operation, share_name, schema_name, table_name, table_version,
+                    predicate_hints, num_files, num_bytes, client_ip, user_agent)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)"#,
+            entry.token_id,
+            entry.operation,
+            entry.share_name,
+            entry.schema_name,
+            entry.table_name,
+            entry.table_version,
+            entry.predicate_hints,
+            entry.num_files,
+            entry.num_bytes,
+            entry.client_ip,
+            entry.user_agent,
+        )
+        .execute(db)
+        .await?;
+        Ok(())
+    }
+
+    /**
+     * Return the most recent entries matching the filter, newest first
+     */
+    #[instrument(name = "AccessLog::list", skip(db))]
+    pub async fn list(filter: &AccessFilter, db: &PgPool) -> Result<Vec<AccessLog>, sqlx::Error> {
+        sqlx::query_as!(
+            AccessLog,
+            r#"SELECT access_log.*, tokens.name AS token_name FROM access_log, tokens
+                WHERE access_log.token_id = tokens.id
+                AND ($1::text IS NULL OR tokens.name = $1)
+                AND ($2::text IS NULL OR access_log.share_name = $2)
+                AND ($3::text IS NULL OR access_log.table_name = $3)
+                AND ($4::timestamptz IS NULL OR access_log.created_at >= $4)
+                AND ($5::timestamptz IS NULL OR access_log.created_at < $5)
+                ORDER BY access_log.created_at DESC
+                LIMIT $6"#,
+            filter.token_name,
+            filter.share_name,
+            filter.table_name,
+            filter.since,
+            filter.until,
+            filter.limit,
+        )
+        .fetch_all(db)
+        .await
+    }
+}
+
+/**
+ * Whoever makes changes to the catalog, through the admin console or the
+ * command line
+ */
+#[derive(Clone, Debug)]
+pub struct Actor {
+    pub name: String,
+    pub source_ip: Option<String>,
+}
 
+impl Actor {
+    /// The entry describing the change made by the actor to the object
+    pub fn entry(
+        &self,
+        action: &'static str,
+        object_type: &'static str,
+        object_id: &Uuid,
+        old_value: Option<serde_json::Value>,
+        new_value: Option<serde_json::Value>,
+    ) -> AuditEntry {
+        AuditEntry {
+            actor: self.name.clone(),
+            action,
+            object_type,
+            object_id: Some(*object_id),
+            old_value,
+            new_value,
+            source_ip: self.source_ip.clone(),
+        }
+    }
+}
+
+/**
+ * A new entry for the admin_audit trail, describing a single change made
+ * through the admin console or the command line
+ */
+#[derive(Clone, Debug)]
+pub struct AuditEntry {
+    pub actor: String,
+    pub action: &'static str,
+    pub object_type: &'static str,
+    pub object_id: Option<Uuid>,
+    pub old_value: Option<serde_json::Value>,
+    pub new_value: Option<serde_json::Value>,
+    pub source_ip: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct AdminAudit {
+    pub id: Uuid,
+    pub actor: String,
+    pub action: String,
+    pub object_type: String,
+    pub object_id: Option<Uuid>,
+    pub old_value: Option<serde_json::Value>,
+    pub new_value: Option<serde_json::Value>,
+    pub source_ip: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl AdminAudit {
+    #[instrument(name = "AdminAudit::record", skip(db))]
+    pub async fn record<'e, E>(entry: &AuditEntry, db: E) -> Result<(), sqlx::Error>
+    where
+        E: sqlx::Executor<'e, Database = sqlx::Postgres>,
+    {
+        sqlx::query!(
+            r#"INSERT INTO admin_audit
+                (actor, action, object_type, object_id, old_value, new_value, source_ip)
+                VALUES ($1, $2, $3, $4, $5, $6, $7)"#,
+            entry.actor,
+            entry.action,
+            entry.object_type,
+            entry.object_id,
+            entry.old_value,
+            entry.new_value,
+            entry.source_ip,
+        )
+        .execute(db)
+        .await?;
+        Ok(())
+    }
+
+    /**
+     * Return the history of changes, newest first, optionally only for the
+     * given type of object or a single object
+     */
+    #[instrument(name = "AdminAudit::list", skip(db))]
+    pub async fn list(
+        object_type: Option<&str>,
+        object_id: Option<&Uuid>,
+        db: &PgPool,
+    ) -> Result<Vec<AdminAudit>, sqlx::Error> {
+        sqlx::query_as!(
+            AdminAudit,
+            r#"SELECT * FROM admin_audit
+                WHERE ($1::text IS NULL OR object_type = $1)
+                AND ($2::uuid IS NULL OR object_id = $2)
+                ORDER BY created_at DESC
+                LIMIT 500"#,
+            object_type,
+            object_id,
+        )
+        .fetch_all(db)
+        .await
+    }
+}
+
+/**
+ * The usage of the sharing API by a token or recipient (the `subject`) within
+ * a calendar minute or day (the `period`), used for enforcing rate limits and
+ * quotas
+ */
+#[derive(Clone, Debug)]
+pub struct UsageCounter {
+    pub subject: String,
+    pub period: String,
+    pub window_start: DateTime<Utc>,
+    pub requests: i64,
+    pub queries: i64,
+    pub bytes: i64,
+}
+
+impl UsageCounter {
+    /**
+     * Add to the counters of the subject's current window, returning the new
+     * totals. The `period` is either "minute" or "day", in UTC
+     */
+    #[instrument(name = "UsageCounter::add", skip(db))]
+    pub async fn add(
+        subject: &str,
+        period: &str,
+        requests: i64,
+        queries: i64,
+        bytes: i64,
+        db: &PgPool,
+    ) -> Result<UsageCounter, sqlx::Error> {
+        sqlx::query_as!(
+            UsageCounter,
+            r#"INSERT INTO usage_counters (subject, period, window_start, requests, queries, bytes)
+                VALUES ($1, $2, date_trunc($2, NOW() AT TIME ZONE 'UTC') AT TIME ZONE 'UTC', $3, $4, $5)
+                ON CONFLICT (subject, period, window_start) DO UPDATE SET
+                    requests = usage_counters.requests + EXCLUDED.requests,
+                    queries = usage_counters.queries + EXCLUDED.queries,
+                    bytes = usage_counters.bytes + EXCLUDED.bytes
+                RETURNING *"#,
+            subject,
+            period,
+            requests,
+            queries,
+            bytes,
+        )
+        .fetch_one(db)
+        .await
+    }
+
+    /**
+     * Take back requests and queries which were added to this counter, e.g.
+     * for a request which was rejected after all
+     */
+    #[instrument(name = "UsageCounter::take_back", skip(db))]
+    pub async fn take_back(
+        &self,
+        requests: i64,
+        queries: i64,
+        db: &PgPool,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"UPDATE usage_counters SET
+                requests = requests - $4,
+                queries = queries - $5
+                WHERE subject = $1 AND period = $2 AND window_start = $3"#,
+            self.subject,
+            self.period,
+            self.window_start,
+            requests,
+            queries,
+        )
+        .execute(db)
+        .await?;
+        Ok(())
+    }
+
+    /**
+     * Remove the subject's counters for windows which have already ended
+     */
+    #[instrument(name = "UsageCounter::prune", skip(db))]
+    pub async fn prune(subject: &str, period: &str, db: &PgPool) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"DELETE FROM usage_counters
+                WHERE subject = $1 AND period = $2
+                AND window_start < date_trunc($2, NOW() AT TIME ZONE 'UTC') AT TIME ZONE 'UTC'"#,
+            subject,
+            period,
+        )
+        .execute(db)
+        .await?;
+        Ok(())
+    }
+
+    /**
+     * The number of seconds until this counter's window ends
+     */
+    pub fn retry_after(&self) -> i64 {
+        let length = match self.period.as_str() {
+            "minute" => chrono::Duration::minutes(1),
+            _ => chrono::Duration::days(1),
+        };
+        (self.window_start + length - Utc::now())
+            .num_seconds()
+            .max(1)
+    }
+}
+
+/**
+ * The latest state of a table as seen by the background table watcher
+ */
+#[derive(Clone, Debug, Serialize)]
+pub struct TableStatus {
+    pub table_id: Uuid,
+    pub version: Option<i64>,
+    pub commit_timestamp: Option<DateTime<Utc>>,
+    pub num_files: Option<i64>,
+    pub size_bytes: Option<i64>,
+    pub healthy: bool,
+    pub error: Option<String>,
+    pub checked_at: DateTime<Utc>,
+}
+
+impl TableStatus {
+    #[instrument(name = "TableStatus::list_all", skip(db))]
+    pub async fn list_all(db: &PgPool) -> Result<Vec<TableStatus>, sqlx::Error> {
+        sqlx::query_as!(TableStatus, "SELECT * FROM table_status")
+            .fetch_all(db)
+            .await
+    }
+
+    /**
+     * Record a successfully loaded snapshot of the table
+     */
+    #[instrument(name = "TableStatus::healthy", skip(snapshot, db), fields(version = snapshot.version))]
+    pub async fn healthy(
+        table_id: &Uuid,
+        snapshot: &Snapshot,
+        db: &PgPool,
+    ) -> Result<(), sqlx::Error> {
+        use chrono::TimeZone;
+
+        let commit_timestamp = snapshot
+            .commit_timestamp
+            .map(|seconds| Utc.timestamp(seconds, 0));
+        sqlx::query!(
+            r#"INSERT INTO table_status
+                (table_id, version, commit_timestamp, num_files, size_bytes, healthy, error)
+                VALUES ($1, $2, $3, $4, $5, TRUE, NULL)
+                ON CONFLICT (table_id) DO UPDATE SET
+                    version = EXCLUDED.version,
+                    commit_timestamp = EXCLUDED.commit_timestamp,
+                    num_files = EXCLUDED.num_files,
+                    size_bytes = EXCLUDED.size_bytes,
+                    healthy = TRUE,
+                    error = NULL,
+                    checked_at = NOW()"#,
+            table_id,
+            snapshot.version,
+            commit_timestamp,
+            snapshot.num_files,
+            snapshot.size,
+        )
+        .execute(db)
+        .await?;
+        Ok(())
+    }
+
+    /**
+     * Mark the table as unhealthy, keeping the details of the last version
+     * which could be loaded
+     */
+    #[instrument(name = "TableStatus::unhealthy", skip(db))]
+    pub async fn unhealthy(table_id: &Uuid, error: &str, db: &PgPool) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"INSERT INTO table_status (table_id, healthy, error)
+                VALUES ($1, FALSE, $2)
+                ON CONFLICT (table_id) DO UPDATE SET
+                    healthy = FALSE,
+                    error = EXCLUDED.error,
+                    checked_at = NOW()"#,
+            table_id,
+            error,
+        )
+        .execute(db)
+        .await?;
+        Ok(())
+    }
+}
+
+/**
+ * A webhook endpoint of a recipient, which is notified whenever a table
+ * shared with the recipient gets a new version
+ */
+#[derive(Clone, Serialize)]
+pub struct Webhook {
+    pub id: Uuid,
+    /// The name of the recipient's tokens
+    pub recipient: String,
+    pub url: String,
+    /// Shared with the recipient for verifying the payload signatures
+    pub secret: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl std::fmt::Debug for Webhook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Webhook")
+            .field("id", &self.id)
+            .field("recipient", &self.recipient)
+            .field("url", &self.url)
+            .field("secret", &REDACTED)
+            .field("created_at", &self.created_at)
+            .finish()
+    }
+}
+
+impl Webhook {
+    #[instrument(name = "Webhook::list_all", skip(db))]
+    pub async fn list_all(db: &PgPool) -> Result<Vec<Webhook>, sqlx::Error> {
+        sqlx::query_as!(Webhook, "SELECT * FROM webhooks ORDER BY created_at")
+            .fetch_all(db)
+            .await
+    }
+
+    #[instrument(name = "Webhook::create", skip(db))]
+    pub async fn create<'e, E>(recipient: &str, url: &str, db: E) -> Result<Webhook, sqlx::Error>
+    where
+        E: sqlx::Executor<'e, Database = sqlx::Postgres>,
+    {
+        let secret = format!(
+            "{}{}",
+            Uuid::new_v4().to_simple(),
+            Uuid::new_v4().to_simple()
+        );
+        sqlx::query_as!(
+            Webhook,
+            r#"INSERT INTO webhooks (id, recipient, url, secret) VALUES ($1, $2, $3, $4) RETURNING *"#,
+            Uuid::new_v4(),
+            recipient,
+            url,
+            secret
+        )
+        .fetch_one(db)
+        .await
+    }
+
+    /**
+     * Queue a delivery of the payload to the webhooks of every recipient
+     * with an unexpired token for the table, returning the number queued
+     */
+    #[instrument(name = "Webhook::enqueue", skip(db))]
+    pub async fn enqueue(
+        table_id: &Uuid,
+        payload: &serde_json::Value,
+        db: &PgPool,
+    ) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query!(
+            r#"INSERT INTO webhook_deliveries (webhook_id, table_id, payload)
+                SELECT DISTINCT webhooks.id, $1::uuid, $2::jsonb
+                FROM webhooks, tokens, tokens_for_tables
+                WHERE webhooks.recipient = tokens.name
+                    AND tokens.expires_at > NOW()
+                    AND tokens.id = tokens_for_tables.token_id
+                    AND tokens_for_tables.table_id = $1"#,
+            table_id,
+            payload
+        )
+        .execute(db)
+        .await?;
+        Ok(result.rows_affected())
+    }
+}
+
+/**
+ * A notification sent, or still to be sent, to a webhook
+ */
+#[derive(Clone, Debug, Serialize)]
+pub struct WebhookDelivery {
+    pub id: Uuid,
+    pub webhook_id: Uuid,
+    pub table_id: Uuid,
+    pub payload: serde_json::Value,
+    pub status: String,
+    pub attempts: i32,
+    pub response_status: Option<i32>,
+    pub error: Option<String>,
+    pub next_attempt_at: DateTime<Utc>,
+    pub delivered_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+/**
+ * A delivery which is due, along with where to send it
+ */
+#[derive(Clone)]
+pub struct PendingDelivery {
+    pub id: Uuid,
+    pub payload: serde_json::Value,
+    pub attempts: i32,
+    pub url: String,
+    pub secret: String,
+}
+
+impl std::fmt::Debug for PendingDelivery {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PendingDelivery")
+            .field("id", &self.id)
+            .field("payload", &self.payload)
+            .field("attempts", &self.attempts)
+            .field("url", &self.url)
+            .field("secret", &REDACTED)
+            .finish()
+    }
+}
+
+impl WebhookDelivery {
+    #[instrument(name = "WebhookDelivery::list", skip(db))]
+    pub async fn list(
+        webhook_id: Option<&Uuid>,
+        db: &PgPool,
+    ) -> Result<Vec<WebhookDelivery>, sqlx::Error> {
+        sqlx::query_as!(
+            WebhookDelivery,
+            r#"SELECT * FROM webhook_deliveries
+                WHERE ($1::uuid IS NULL OR webhook_id = $1)
+                ORDER BY created_at DESC
+                LIMIT 500"#,
+            webhook_id
+        )
+        .fetch_all(db)
+        .await
+    }
+
+    #[instrument(name = "WebhookDelivery::due", skip(db))]
+    pub async fn due(limit: i64, db: &PgPool) -> Result<Vec<PendingDelivery>, sqlx::Error> {
+        sqlx::query_as!(
+            PendingDelivery,
+            r#"SELECT webhook_deliveries.id, payload, attempts, url, secret
+                FROM webhook_deliveries, webhooks
+                WHERE webhook_deliveries.webhook_id = webhooks.id
+                    AND status = 'pending'
+                    AND next_attempt_at <= NOW()
+                ORDER BY next_attempt_at
+                LIMIT $1"#,
+            limit
+        )
+        .fetch_all(db)
+        .await
+    }
+
+    #[instrument(name = "WebhookDelivery::delivered", skip(db))]
+    pub async fn delivered(
+        id: &Uuid,
+        response_status: i32,
+        db: &PgPool,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"UPDATE webhook_deliveries SET
+                status = 'delivered',
+                attempts = attempts + 1,
+                response_status = $2,
+                error = NULL,
+                delivered_at = NOW()
+                WHERE id = $1"#,
+            id,
+            response_status
+        )
+        .execute(db)
+        .await?;
+        Ok(())
+    }
+
+    /**
+     * Record a failed attempt, retrying at `retry_at` or giving up on the
+     * delivery when there is none
+     */
+    #[instrument(name = "WebhookDelivery::attempt_failed", skip(db))]
+    pub async fn attempt_failed(
+        id: &Uuid,
+        response_status: Option<i32>,
+        error: &str,
+        retry_at: Option<DateTime<Utc>>,
+        db: &PgPool,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"UPDATE webhook_deliveries SET
+                status = CASE WHEN $4::timestamptz IS NULL THEN 'failed' ELSE 'pending' END,
+                attempts = attempts + 1,
+                response_status = $2,
+                error = $3,
+                next_attempt_at = COALESCE($4, next_attempt_at)
+                WHERE id = $1"#,
+            id,
+            response_status,
+            error,
+            retry_at
+        )
+        .execute(db)
+        .await?;
+        Ok(())
+    }
+}
+
+/// How long presigned URLs are valid for
+// TODO: make this configurable
+const PRESIGN_EXPIRY: std::time::Duration = std::time::Duration::from_secs(300);
+
+/**
+ * The line of a query response for an add action of the Delta log, with the
+ * presigned URL of its data file in place of its path
+ */
+fn add_action(
+    table_uri: &str,
+    mut action: serde_json::Value,
+    expiration: i64,
+    presign: impl Fn(&str) -> Result<String, DeltaTableError>,
+) -> Result<serde_json::Value, DeltaTableError> {
+    use serde_json::json;
+
+    let file = format!(
+        "{}/{}",
+        table_uri,
+        action["path"].as_str().unwrap_or_default()
+    );
+    action["path"] = json!(presign(&file)?);
+
+    Ok(json!({
+        "file" : {
+            "id" : file_id(&file),
+            "expirationTimestamp" : expiration,
+            "deltaSingleAction" : { "add" : action },
+        }
+    }))
+}
+
+/**
+ * Why the files of a table could not be handed out
+ */
+#[derive(Debug)]
+pub enum PresignError {
+    /// There are no credentials to sign with, which may well be temporary
+    Credentials(rusoto_credential::CredentialsError),
+    Table(DeltaTableError),
+}
+
+impl std::fmt::Display for PresignError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PresignError::Credentials(e) => write!(f, "Failed to get credentials: {}", e),
+            PresignError::Table(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for PresignError {}
+
+impl From<DeltaTableError> for PresignError {
+    fn from(e: DeltaTableError) -> Self {
+        PresignError::Table(e)
+    }
+}
+
+impl From<serde_json::Error> for PresignError {
+    fn from(e: serde_json::Error) -> Self {
+        PresignError::Table(e.into())
+    }
+}
+
+/**
+ * Presigns GET requests for the files of tables, with the credentials from
+ * the environment
+ */
+struct Presigner {
+    region: rusoto_core::Region,
+    credentials: rusoto_credential::AwsCredentials,
+}
+
+impl Presigner {
+    async fn new(storage: &StorageConfig) -> Result<Self, PresignError> {
+        use rusoto_core::Region;
+        use rusoto_credential::{ChainProvider, ProvideAwsCredentials};
+
+        let region = match (&storage.endpoint_url, &storage.region) {
+            (Some(endpoint), region) => Region::Custom {
+                name: region.clone().unwrap_or_else(|| "custom".to_string()),
+                endpoint: endpoint.clone(),
+            },
+            // The region has been validated along with the configuration
+            (None, Some(region)) => region.parse().unwrap_or_default(),
+            (None, None) => Region::default(),
+        };
+        let credentials = ChainProvider::new().credentials().await.map_err(|e| {
+            metrics::PRESIGN_FAILURES.inc();
+            PresignError::Credentials(e)
+        })?;
+
+        Ok(Self {
+            region,
+            credentials,
+        })
+    }
+
+    fn presign(&self, file: &str) -> Result<String, DeltaTableError> {
+        use rusoto_s3::util::{PreSignedRequest, PreSignedRequestOption};
+        use rusoto_s3::GetObjectRequest;
+
+        let s3obj = deltalake::storage::parse_uri(file)
+            .and_then(|uri| uri.into_s3object())
+            .map_err(|e| {
+                metrics::PRESIGN_FAILURES.inc();
+                e
+            })?;
+        let req = GetObjectRequest {
+            bucket: s3obj.bucket.to_string(),
+            key: s3obj.key.to_string(),
+            ..Default::default()
+        };
+        debug!("get request: {:?}", req);
+        let options = PreSignedRequestOption {
+            expires_in: PRESIGN_EXPIRY,
+        };
+        Ok(req.get_presigned_url(&self.region, &self.credentials, &options))
+    }
+}
+
+/**
+ * The id of a file, which the delta response format requires for every file
+ * rather than only for those named like Spark names them
+ */
+fn file_id(file: &str) -> String {
+    use sha2::{Digest, Sha256};
+
+    id_from_file(file)
+        .map(str::to_string)
+        .unwrap_or_else(|| hex::encode(Sha256::digest(file.as_bytes())))
+}
+
+lazy_static! {
+    /// The names Spark gives to the data files it writes
+    static ref PART_FILE: Regex =
+        Regex::new(r"part-(\d{5})-([a-z,0-9,\-]{36})-([a-z,0-9]{4}).(\w+).parquet").unwrap();
+}
+
+fn id_from_file(file: &str) -> Option<&str> {
     let parts: Vec<&str> = file.split('/').collect();
-    // TODO: Move this to a lazy_static!
-    let re = Regex::new(r"part-(\d{5})-([a-z,0-9,\-]{36})-([a-z,0-9]{4}).(\w+).parquet").unwrap();
-    let captured = re.captures(parts.last()?)?;
+    let captured = PART_FILE.captures(parts.last()?)?;
     if captured.len() == 5 {
         return Some(captured.get(2).unwrap().as_str());
     }
@@ -483,6 +1682,144 @@ fn id_from_file(file: &str) -> Option<&str> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_usage_counter_retry_after() {
+        let counter = UsageCounter {
+            subject: "token:test".to_string(),
+            period: "minute".to_string(),
+            window_start: Utc::now() - chrono::Duration::seconds(45),
+            requests: 61,
+            queries: 0,
+            bytes: 0,
+        };
+        let retry = counter.retry_after();
+        assert!(retry > 0 && retry <= 15, "unexpected Retry-After {}", retry);
+
+        let stale = UsageCounter {
+            window_start: Utc::now() - chrono::Duration::days(2),
+            ..counter
+        };
+        assert_eq!(1, stale.retry_after());
+    }
+
+    #[test]
+    fn test_metadata_stats() {
+        let mut metadata = Metadata {
+            id: Uuid::new_v4().to_string(),
+            name: None,
+            description: None,
+            format: serde_json::from_str(r#"{"provider":"parquet","options":{}}"#).unwrap(),
+            schema_string: "{}".to_string(),
+            partition_columns: vec![],
+            configuration: HashMap::new(),
+            created_time: 0,
+            num_files: None,
+            size: None,
+        };
+        let json = serde_json::to_value(&metadata).unwrap();
+        assert!(json.get("numFiles").is_none());
+        assert!(json.get("size").is_none());
+
+        metadata.num_files = Some(3);
+        metadata.size = Some(4096);
+        let json = serde_json::to_value(&metadata).unwrap();
+        assert_eq!(Some(3), json["numFiles"].as_i64());
+        assert_eq!(Some(4096), json["size"].as_i64());
+    }
+
+    #[test]
+    fn test_delta_metadata_action() {
+        let metadata = DeltaTableMetaData {
+            id: "5fba94ed-9794-4965-ba6e-6ee3c0d22af9".to_string(),
+            name: None,
+            description: Some("Ingredients".to_string()),
+            format: serde_json::from_str(r#"{"provider":"parquet","options":{}}"#).unwrap(),
+            schema: serde_json::from_str(r#"{"type":"struct","fields":[]}"#).unwrap(),
+            partition_columns: vec![],
+            created_time: 1620000000000,
+            configuration: vec![(
+                "delta.enableChangeDataFeed".to_string(),
+                Some("true".to_string()),
+            )]
+            .into_iter()
+            .collect(),
+        };
+        let json =
+            serde_json::to_value(DeltaMetadataAction::from_metadata(&metadata).unwrap()).unwrap();
+
+        assert_eq!("5fba94ed-9794-4965-ba6e-6ee3c0d22af9", json["id"]);
+        assert_eq!("Ingredients", json["description"]);
+        assert_eq!("true", json["configuration"]["delta.enableChangeDataFeed"]);
+        assert_eq!(1620000000000i64, json["createdTime"]);
+    }
+
+    #[async_std::test]
+    async fn test_protocol_reader_features() {
+        let location = crate::cache::tests::table_with(
+            serde_json::json!({
+                "minReaderVersion": 3,
+                "minWriterVersion": 7,
+                "readerFeatures": ["columnMapping"],
+                "writerFeatures": ["columnMapping"],
+            }),
+            serde_json::json!({"delta.columnMapping.mode": "name"}),
+        );
+        let schema = Schema {
+            id: Uuid::new_v4(),
+            name: "acme_vaccine_data".to_string(),
+            share_id: Uuid::new_v4(),
+            share_name: "vaccine_share".to_string(),
+            created_at: Utc::now(),
+        };
+        let inner = PrimitiveTable {
+            id: Uuid::new_v4(),
+            name: "vaccine_ingredients".to_string(),
+            location: location.clone(),
+            schema_id: schema.id,
+            created_at: Utc::now(),
+            description: None,
+        };
+        let mut table = Table::new(inner, schema);
+        let cache = TableCache::new(crate::config::TableCacheConfig::default());
+        table.load_delta(&cache).await.unwrap();
+
+        let json = serde_json::to_value(table.delta_protocol().unwrap()).unwrap();
+        assert_eq!(3, json["deltaProtocol"]["minReaderVersion"]);
+        assert_eq!(
+            serde_json::json!(["columnMapping"]),
+            json["deltaProtocol"]["readerFeatures"]
+        );
+
+        let json = serde_json::to_value(table.protocol().unwrap()).unwrap();
+        assert!(json.get("readerFeatures").is_none());
+        std::fs::remove_dir_all(&location).unwrap();
+    }
+
+    #[test]
+    fn test_add_action() {
+        let add = serde_json::json!({
+            "path" : "part-00000-d0ec7722-b30c-4e1c-92cd-b4fe8d3bb954-c000.snappy.parquet",
+            "partitionValues" : {},
+            "size" : 1024,
+            "modificationTime" : 0,
+            "dataChange" : true,
+        });
+        let line = add_action("s3://bucket/table", add, 1000, |path| {
+            Ok(format!("https://signed/{}", path))
+        })
+        .unwrap();
+
+        let file = &line["file"];
+        assert_eq!("d0ec7722-b30c-4e1c-92cd-b4fe8d3bb954", file["id"]);
+        assert_eq!(1000, file["expirationTimestamp"]);
+        let action = &file["deltaSingleAction"]["add"];
+        assert_eq!(
+            "https://signed/s3://bucket/table/part-00000-d0ec7722-b30c-4e1c-92cd-b4fe8d3bb954-c000.snappy.parquet",
+            action["path"]
+        );
+        assert_eq!(1024, action["size"]);
+    }
+
     #[test]
     fn test_id_from_file() {
         let file = "s3://delta-riverbank/COVID-19_NYT/part-00006-d0ec7722-b30c-4e1c-92cd-b4fe8d3bb954-c000.snappy.parquet";
@@ -0,0 +1,341 @@
+/*
+ * The oidc module implements the OpenID Connect authorization code flow which
+ * can be used for logging into the admin console with an external identity
+ * provider
+ */
+use jsonwebtoken::{Algorithm, Validation};
+use log::*;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use tide::http::Url;
+
+use crate::config::OidcConfig;
+use crate::jwt::{is_rsa, Jwks};
+use crate::state::Role;
+
+/**
+ * The subset of the provider's discovery document which riverbank needs
+ */
+#[derive(Clone, Debug, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+    #[serde(default, deserialize_with = "known_algorithms")]
+    id_token_signing_alg_values_supported: Vec<Algorithm>,
+}
+
+/**
+ * Providers advertise algorithms which jsonwebtoken doesn't know, such as
+ * `EdDSA` or `none`, which are skipped rather than failing the discovery
+ */
+fn known_algorithms<'de, D>(deserializer: D) -> Result<Vec<Algorithm>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let algorithms: Vec<String> = Deserialize::deserialize(deserializer)?;
+    Ok(algorithms
+        .iter()
+        .filter_map(|alg| alg.parse::<Algorithm>().ok())
+        .collect())
+}
+
+/**
+ * The claims from a validated ID token
+ */
+#[derive(Clone, Debug, Deserialize)]
+pub struct IdentityClaims {
+    pub sub: String,
+    pub nonce: Option<String>,
+    pub email: Option<String>,
+    pub preferred_username: Option<String>,
+    #[serde(flatten)]
+    extra: HashMap<String, Value>,
+}
+
+impl IdentityClaims {
+    /**
+     * Return the most human friendly name the provider gave us for the user
+     */
+    pub fn username(&self) -> &str {
+        self.preferred_username
+            .as_ref()
+            .or_else(|| self.email.as_ref())
+            .unwrap_or(&self.sub)
+    }
+
+    /**
+     * Return the groups found in the given claim, which some providers send
+     * as a single string rather than an array
+     */
+    fn groups(&self, claim: &str) -> Vec<&str> {
+        match self.extra.get(claim) {
+            Some(Value::Array(groups)) => groups.iter().filter_map(|g| g.as_str()).collect(),
+            Some(Value::String(group)) => vec![group],
+            _ => vec![],
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct OidcClient {
+    config: OidcConfig,
+    provider: ProviderMetadata,
+    jwks: Jwks,
+}
+
+impl OidcClient {
+    /**
+     * Create the client by fetching the discovery document and signing keys
+     * from the configured issuer
+     */
+    pub async fn discover(config: &OidcConfig) -> Result<OidcClient, tide::Error> {
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            config.issuer.trim_end_matches('/')
+        );
+        debug!("Fetching OpenID Connect discovery document from {}", url);
+        let provider: ProviderMetadata = surf::get(&url).recv_json().await?;
+
+        if provider.issuer.trim_end_matches('/') != config.issuer.trim_end_matches('/') {
+            return Err(tide::Error::from_str(
+                500,
+                format!(
+                    "The OpenID Connect provider reported issuer {} instead of {}",
+                    provider.issuer, config.issuer
+                ),
+            ));
+        }
+
+        let jwks = Jwks::fetch(&provider.jwks_uri).await?;
+
+        Ok(OidcClient {
+            config: config.clone(),
+            provider,
+            jwks,
+        })
+    }
+
+    /**
+     * Build the URL of the provider's login page which the admin should be
+     * redirected to
+     */
+    pub fn authorize_url(&self, state: &str, nonce: &str) -> Result<Url, tide::Error> {
+        let mut url = Url::parse(&self.provider.authorization_endpoint)?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.config.client_id)
+            .append_pair("redirect_uri", &self.config.redirect_url)
+            .append_pair("scope", &self.config.scopes.join(" "))
+            .append_pair("state", state)
+            .append_pair("nonce", nonce);
+        Ok(url)
+    }
+
+    /**
+     * Exchange the authorization code from the callback for an ID token and
+     * return its validated claims
+     */
+    pub async fn exchange(&self, code: &str) -> Result<IdentityClaims, tide::Error> {
+        #[derive(Deserialize, Debug)]
+        struct TokenResponse {
+            id_token: String,
+        }
+
+        let form = tide::Body::from_form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", self.config.redirect_url.as_str()),
+            ("client_id", self.config.client_id.as_str()),
+            ("client_secret", self.config.client_secret.as_str()),
+        ])?;
+        let mut response = surf::post(&self.provider.token_endpoint)
+            .header("Accept", "application/json")
+            .body(form)
+            .await?;
+
+        if !response.status().is_success() {
+            let body = response.body_string().await.unwrap_or_default();
+            error!(
+                "The token endpoint returned {}: {}",
+                response.status(),
+                body
+            );
+            return Err(tide::Error::from_str(
+                502,
+                "Failed to exchange the authorization code",
+            ));
+        }
+
+        let token: TokenResponse = response.body_json().await?;
+
+        // Only the RSA algorithms advertised by the provider are accepted
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.algorithms.extend(
+            self.provider
+                .id_token_signing_alg_values_supported
+                .iter()
+                .filter(|alg| **alg != Algorithm::RS256 && is_rsa(alg))
+                .copied(),
+        );
+        validation.iss = Some(self.provider.issuer.clone());
+        validation.set_audience(&[&self.config.client_id]);
+
+        let data = self
+            .jwks
+            .decode::<IdentityClaims>(&token.id_token, &validation)
+            .await
+            .map_err(|e| tide::Error::from_str(401, format!("Invalid ID token: {}", e)))?;
+        Ok(data.claims)
+    }
+
+    /**
+     * Map the group claims of an authenticated user to a riverbank role,
+     * returning None if the user isn't a member of any configured group
+     */
+    pub fn role_for(&self, claims: &IdentityClaims) -> Option<Role> {
+        let groups = claims.groups(&self.config.groups_claim);
+        let member_of =
+            |configured: &[String]| configured.iter().any(|g| groups.contains(&g.as_str()));
+
+        if member_of(&self.config.admin_groups) {
+            Some(Role::Admin)
+        } else if member_of(&self.config.viewer_groups) {
+            Some(Role::Viewer)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+    use tide::{Body, Request};
+
+    const TEST_KEY: &[u8] = include_bytes!("../tests/fixtures/jwt-rsa.pem");
+    const TEST_JWKS: &str = include_str!("../tests/fixtures/jwks.json");
+
+    /**
+     * Start a stand-in OpenID Connect provider on a random local port which
+     * hands out ID tokens with the given groups claim for the code
+     * "valid-code", returning the issuer URL
+     */
+    async fn mock_provider(groups: Value) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let mut app = tide::with_state((issuer.clone(), groups));
+
+        app.at("/.well-known/openid-configuration").get(
+            |req: Request<(String, Value)>| async move {
+                let issuer = &req.state().0;
+                Body::from_json(&json!({
+                    "issuer" : issuer,
+                    "authorization_endpoint" : format!("{}/authorize", issuer),
+                    "token_endpoint" : format!("{}/token", issuer),
+                    "jwks_uri" : format!("{}/jwks", issuer),
+                    "id_token_signing_alg_values_supported" : ["RS256", "EdDSA", "none"],
+                }))
+            },
+        );
+        app.at("/jwks")
+            .get(|_req: Request<(String, Value)>| async move { Ok(TEST_JWKS) });
+        app.at("/token")
+            .post(|mut req: Request<(String, Value)>| async move {
+                let form: HashMap<String, String> = req.body_form().await?;
+                if form.get("code").map(String::as_str) != Some("valid-code") {
+                    return Ok(tide::Response::builder(400)
+                        .body(json!({"error" : "invalid_grant"}))
+                        .build());
+                }
+
+                let (issuer, groups) = req.state();
+                let claims = json!({
+                    "iss" : issuer,
+                    "aud" : "riverbank",
+                    "sub" : "1234",
+                    "exp" : chrono::Utc::now().timestamp() + 300,
+                    "nonce" : "test-nonce",
+                    "preferred_username" : "alice",
+                    "groups" : groups,
+                });
+                let mut header = Header::new(Algorithm::RS256);
+                header.kid = Some("riverbank-test".to_string());
+                let id_token =
+                    jsonwebtoken::encode(&header, &claims, &EncodingKey::from_rsa_pem(TEST_KEY)?)?;
+
+                Ok(tide::Response::builder(200)
+                    .body(json!({
+                        "access_token" : "unused",
+                        "token_type" : "Bearer",
+                        "id_token" : id_token,
+                    }))
+                    .build())
+            });
+
+        async_std::task::spawn(async move { app.listen(listener).await });
+        issuer
+    }
+
+    fn config_for(issuer: &str) -> OidcConfig {
+        OidcConfig {
+            issuer: issuer.to_string(),
+            client_id: "riverbank".to_string(),
+            client_secret: "hunter2".to_string(),
+            redirect_url: "http://localhost:8000/admin/oidc/callback".to_string(),
+            scopes: vec!["openid".to_string()],
+            groups_claim: "groups".to_string(),
+            admin_groups: vec!["riverbank-admins".to_string()],
+            viewer_groups: vec!["data-engineering".to_string()],
+        }
+    }
+
+    #[async_std::test]
+    async fn test_authorization_code_flow() {
+        let issuer = mock_provider(json!(["everyone", "riverbank-admins"])).await;
+        let client = OidcClient::discover(&config_for(&issuer))
+            .await
+            .expect("Failed to discover the mock provider");
+
+        let url = client.authorize_url("test-state", "test-nonce").unwrap();
+        assert_eq!(
+            format!("{}/authorize", issuer),
+            url[..tide::http::url::Position::AfterPath]
+        );
+        assert!(url
+            .query_pairs()
+            .any(|(k, v)| k == "state" && v == "test-state"));
+
+        let claims = client
+            .exchange("valid-code")
+            .await
+            .expect("Failed to exchange the code");
+        assert_eq!("alice", claims.username());
+        assert_eq!(Some("test-nonce".to_string()), claims.nonce);
+        assert_eq!(Some(Role::Admin), client.role_for(&claims));
+    }
+
+    #[async_std::test]
+    async fn test_viewer_and_unknown_groups() {
+        let issuer = mock_provider(json!("data-engineering")).await;
+        let client = OidcClient::discover(&config_for(&issuer)).await.unwrap();
+        let claims = client.exchange("valid-code").await.unwrap();
+        assert_eq!(Some(Role::Viewer), client.role_for(&claims));
+
+        let issuer = mock_provider(json!(["marketing"])).await;
+        let client = OidcClient::discover(&config_for(&issuer)).await.unwrap();
+        let claims = client.exchange("valid-code").await.unwrap();
+        assert_eq!(None, client.role_for(&claims));
+    }
+
+    #[async_std::test]
+    async fn test_invalid_code() {
+        let issuer = mock_provider(json!([])).await;
+        let client = OidcClient::discover(&config_for(&issuer)).await.unwrap();
+        assert!(client.exchange("bogus").await.is_err());
+    }
+}
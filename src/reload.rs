@@ -0,0 +1,233 @@
+/*
+ * The reload module re-reads the configuration file when it changes or on
+ * SIGHUP, and swaps the new configuration in if it is valid
+ */
+use async_std::channel::Receiver;
+use async_std::sync::Arc;
+use futures_lite::FutureExt;
+use log::*;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::catalog::{file::FileCatalog, CatalogStore};
+use crate::cli::Overrides;
+use crate::config::Config;
+use crate::jwt::JwtIssuer;
+use crate::server::Signal;
+use crate::state::{AppState, Reloadable};
+
+/**
+ * Reload the configuration on SIGHUP and, unless disabled, whenever the
+ * modification time of the file changes, forever
+ */
+pub async fn watch(
+    state: AppState<'static>,
+    path: PathBuf,
+    overrides: Overrides,
+    signals: Receiver<Signal>,
+) {
+    let interval = state.config().reload.interval_seconds;
+    let mut modified = modified_at(&path);
+
+    loop {
+        let signaled = async {
+            match signals.recv().await {
+                Ok(signal) => signal == Signal::Reload,
+                Err(_) => futures_lite::future::pending().await,
+            }
+        };
+        let polled = async {
+            if interval > 0 {
+                async_std::task::sleep(Duration::from_secs(interval)).await;
+            } else {
+                futures_lite::future::pending::<()>().await;
+            }
+            false
+        };
+        let signaled = signaled.or(polled).await;
+
+        let current = modified_at(&path);
+        if signaled || current != modified {
+            modified = current;
+            reload(&state, &path, &overrides).await;
+        }
+    }
+}
+
+fn modified_at(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/**
+ * Load and validate the configuration file, swapping it in for the current
+ * one only if that all succeeds
+ */
+pub async fn reload(state: &AppState<'_>, path: &Path, overrides: &Overrides) {
+    let current = state.reloadable();
+
+    match load(&current, path, overrides).await {
+        Ok(reloaded) => {
+            let changes = diff(&current.config, &reloaded.config);
+            if changes.is_empty() {
+                debug!("The configuration in {} is unchanged", path.display());
+                return;
+            }
+            state.swap(reloaded);
+            info!(
+                "Reloaded the configuration from {}:\n{}",
+                path.display(),
+                changes.join("\n")
+            );
+        }
+        Err(e) => error!(
+            "Failed to reload the configuration from {}, keeping the current one: {}",
+            path.display(),
+            e
+        ),
+    }
+}
+
+async fn load(
+    current: &Reloadable,
+    path: &Path,
+    overrides: &Overrides,
+) -> Result<Reloadable, String> {
+    let mut config =
+        Config::load(path, |config| overrides.apply(config)).map_err(|e| e.to_string())?;
+    keep_restart_only(&mut config, &current.config);
+
+    let catalog: Arc<dyn CatalogStore> = if config.catalog.is_read_only() {
+        Arc::new(FileCatalog::new(&config.shares, &config.recipients).map_err(|e| e.to_string())?)
+    } else {
+        current.catalog.clone()
+    };
+
+    let jwt_issuers =
+        if format!("{:?}", config.jwt_issuers) == format!("{:?}", current.config.jwt_issuers) {
+            current.jwt_issuers.clone()
+        } else {
+            let mut issuers = vec![];
+            for issuer in &config.jwt_issuers {
+                issuers.push(JwtIssuer::load(issuer).await.map_err(|e| e.to_string())?);
+            }
+            Arc::new(issuers)
+        };
+
+    Ok(Reloadable {
+        config: Arc::new(config),
+        catalog,
+        jwt_issuers,
+    })
+}
+
+/**
+ * Keep the sections which are only read on start from the current
+ * configuration, warning about the changes which need a restart
+ */
+fn keep_restart_only(config: &mut Config, current: &Config) {
+    macro_rules! keep {
+        ($($section:ident),*) => {$(
+            if format!("{:?}", config.$section) != format!("{:?}", current.$section) {
+                warn!(
+                    "The {} section changed, which only takes effect after a restart",
+                    stringify!($section)
+                );
+            }
+            config.$section = current.$section.clone();
+        )*};
+    }
+    keep!(
+        server,
+        database,
+        admin,
+        catalog,
+        oidc,
+        table_cache,
+        logging,
+        tracing,
+        watcher,
+        webhooks,
+        reload
+    );
+}
+
+/**
+ * Flatten the pretty printed configuration into one line per value, prefixed
+ * with the sections it is in. Secrets are redacted by the Debug impls
+ */
+fn flatten(config: &Config) -> Vec<String> {
+    let mut sections: Vec<String> = vec![];
+    let mut lines = vec![];
+
+    for line in format!("{:#?}", config).lines().skip(1) {
+        let depth = (line.len() - line.trim_start().len()) / 4;
+        let line = line.trim().trim_end_matches(',');
+        sections.truncate(depth.saturating_sub(1));
+
+        if line.ends_with(|c| c == '{' || c == '[' || c == '(') {
+            let section = line.split(": ").next().unwrap_or(line);
+            sections.push(section.trim_end_matches(|c| " {[(".contains(c)).to_string());
+        } else if line.contains(": ") {
+            let prefix: String = sections.iter().map(|s| format!("{}.", s)).collect();
+            lines.push(format!("{}{}", prefix, line));
+        } else if !matches!(line, "}" | "]" | ")") {
+            // The values inside Some(..) and lists
+            lines.push(format!("{}: {}", sections.join("."), line));
+        }
+    }
+    lines
+}
+
+/**
+ * The lines removed from and added to the configuration, for the log
+ */
+fn diff(old: &Config, new: &Config) -> Vec<String> {
+    let old = flatten(old);
+    let new = flatten(new);
+
+    old.iter()
+        .filter(|line| !new.contains(line))
+        .map(|line| format!("- {}", line))
+        .chain(
+            new.iter()
+                .filter(|line| !old.contains(line))
+                .map(|line| format!("+ {}", line)),
+        )
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(yaml: &str) -> Config {
+        serde_yaml::from_str(yaml).expect("Failed to parse the configuration")
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = config("rate_limits:\n  per_token:\n    requests_per_minute: 10\n");
+        let new = config("rate_limits:\n  per_token:\n    requests_per_minute: 20\n");
+
+        assert!(diff(&old, &old).is_empty());
+        assert_eq!(
+            vec![
+                "- rate_limits.per_token.requests_per_minute: 10",
+                "+ rate_limits.per_token.requests_per_minute: 20",
+            ],
+            diff(&old, &new)
+        );
+    }
+
+    #[test]
+    fn test_keep_restart_only() {
+        let current = config("server:\n  bind: '0.0.0.0:8000'\n");
+        let mut reloaded = config(
+            "server:\n  bind: '0.0.0.0:9000'\nrate_limits:\n  per_token:\n    requests_per_minute: 5\n",
+        );
+        keep_restart_only(&mut reloaded, &current);
+
+        assert_eq!("0.0.0.0:8000", reloaded.server.bind);
+        assert_eq!(Some(5), reloaded.rate_limits.per_token.requests_per_minute);
+    }
+}
@@ -1,58 +1,615 @@
+use crate::config::AdminConfig;
+use crate::metrics;
 use crate::models::*;
-use crate::state::{AppState, User};
+use crate::state::{AppState, Role};
 use log::*;
 use serde::Deserialize;
 use serde_json::json;
+use tide::http::cookies::SameSite;
+use tide::sessions::{CookieStore, SessionMiddleware};
 use tide::{Body, Request};
 use uuid::Uuid;
 
+/// Session key holding the name of the logged in admin user
+const SESSION_USER: &str = "user";
+/// Session key holding the Role of the logged in admin user
+const SESSION_ROLE: &str = "role";
+/// Session key holding the CSRF token which must accompany every mutating form
+const SESSION_CSRF: &str = "csrf_token";
+/// Session keys holding the state and nonce of an in-flight OpenID Connect login
+const SESSION_OIDC_STATE: &str = "oidc_state";
+const SESSION_OIDC_NONCE: &str = "oidc_nonce";
+
+/// Paths under /admin which can be reached without being logged in
+const PUBLIC_PATHS: &[&str] = &["/login", "/oidc/login", "/oidc/callback"];
+/// Paths under /admin which users without the admin role may still POST to
+const VIEWER_PATHS: &[&str] = &["/logout"];
+
 #[derive(Default)]
 struct AdminAuthentication;
 
 #[tide::utils::async_trait]
 impl<AppState: Clone + Send + Sync + 'static> tide::Middleware<AppState> for AdminAuthentication {
     async fn handle(&self, req: Request<AppState>, next: tide::Next<'_, AppState>) -> tide::Result {
-        if let Some(_user) = req.ext::<User>() {
+        use tide::http::Method;
+
+        let path = req.url().path().to_string();
+        if PUBLIC_PATHS.contains(&path.as_str()) {
+            return Ok(next.run(req).await);
+        }
+
+        match req.session().get::<Role>(SESSION_ROLE) {
+            Some(role)
+                if role.can_edit()
+                    || req.method() == Method::Get
+                    || VIEWER_PATHS.contains(&path.as_str()) =>
+            {
+                Ok(next.run(req).await)
+            }
+            Some(_) => Ok(tide::Response::builder(403)
+                .body("Your role does not allow changes to be made")
+                .build()),
+            None => Ok(tide::Redirect::new("/admin/login").into()),
+        }
+    }
+}
+
+/**
+ * The CsrfMiddleware ensures every session has a CSRF token and that every
+ * mutating request carries the same token, either in the `csrf_token` form
+ * field or the `X-CSRF-Token` header
+ */
+#[derive(Default)]
+struct CsrfMiddleware;
+
+#[tide::utils::async_trait]
+impl<AppState: Clone + Send + Sync + 'static> tide::Middleware<AppState> for CsrfMiddleware {
+    async fn handle(
+        &self,
+        mut req: Request<AppState>,
+        next: tide::Next<'_, AppState>,
+    ) -> tide::Result {
+        use tide::http::Method;
+
+        let expected = match req.session().get::<String>(SESSION_CSRF) {
+            Some(token) => token,
+            None => {
+                let token = Uuid::new_v4().to_hyphenated().to_string();
+                req.session_mut().insert(SESSION_CSRF, &token)?;
+                token
+            }
+        };
+
+        if matches!(
+            req.method(),
+            Method::Get | Method::Head | Method::Options | Method::Trace
+        ) {
+            return Ok(next.run(req).await);
+        }
+
+        let provided = if let Some(header) = req.header("X-CSRF-Token") {
+            Some(header.last().as_str().to_string())
+        } else {
+            let mime = req.content_type();
+            let params = req.body_string().await?;
+            let token = csrf_from_form(&params);
+            // Put the body back so the handler can deserialize the form
+            let mut body = Body::from_string(params);
+            if let Some(mime) = mime {
+                body.set_mime(mime);
+            }
+            req.set_body(body);
+            token
+        };
+
+        if provided.as_deref() == Some(expected.as_str()) {
             Ok(next.run(req).await)
         } else {
-            let mut response: tide::Response = "howdy stranger".to_string().into();
-            response.set_status(tide::http::StatusCode::Unauthorized);
-            response.insert_header("WWW-Authenticate", "Basic");
-            Ok(response)
+            warn!(
+                "Rejecting {} {} with a missing or invalid CSRF token",
+                req.method(),
+                req.url()
+            );
+            Ok(tide::Response::builder(403)
+                .body("Invalid CSRF token")
+                .build())
         }
     }
 }
 
+/**
+ * Pull the csrf_token field out of a url-encoded form body
+ */
+fn csrf_from_form(params: &str) -> Option<String> {
+    #[derive(Deserialize, Debug)]
+    struct CsrfForm {
+        csrf_token: Option<String>,
+    }
+
+    serde_qs::Config::new(5, false)
+        .deserialize_str::<CsrfForm>(params)
+        .ok()
+        .and_then(|form| form.csrf_token)
+}
+
+/**
+ * Return the secret used for signing the session cookies.
+ *
+ * Without a configured secret a random one is generated, which means admin
+ * sessions will not survive a restart of the server
+ */
+fn session_secret(config: &AdminConfig) -> Vec<u8> {
+    match &config.session_secret {
+        // The length has been validated along with the configuration
+        Some(secret) => secret.clone().into_bytes(),
+        None => {
+            warn!("RIVERBANK_SESSION_SECRET is not set, generating a random session secret");
+            let mut secret = Uuid::new_v4().as_bytes().to_vec();
+            secret.extend_from_slice(Uuid::new_v4().as_bytes());
+            secret
+        }
+    }
+}
+
+/// Where the routes are nested on the server
+const PREFIX: &str = "/admin";
+
 pub fn register(app: &mut tide::Server<AppState<'static>>) {
     let mut admin = tide::with_state(app.state().clone());
 
-    admin.with(tide_http_auth::Authentication::new(
-        tide_http_auth::BasicAuthScheme::default(),
-    ));
+    // The session cookie must be sent along when the identity provider
+    // redirects back to the OpenID Connect callback, so it cannot be strict
+    admin.with(
+        SessionMiddleware::new(
+            CookieStore::new(),
+            &session_secret(&app.state().config().admin),
+        )
+        .with_same_site_policy(SameSite::Lax),
+    );
+    admin.with(CsrfMiddleware {});
     admin.with(AdminAuthentication {});
-    admin.at("/").get(index);
-    admin.at("/tokens").post(create_token);
-    admin.at("/tokens/share/:id").get(download_share);
-    admin.at("/tables").post(create_table);
-    admin.at("/schemas").post(create_schema);
-    admin.at("/shares").post(create_share);
-    app.at("/admin").nest(admin);
+    metrics::at(&mut admin, PREFIX, "/").get(index);
+    metrics::at(&mut admin, PREFIX, "/login")
+        .get(login_form)
+        .post(login);
+    metrics::at(&mut admin, PREFIX, "/logout").post(logout);
+    metrics::at(&mut admin, PREFIX, "/oidc/login").get(oidc_login);
+    metrics::at(&mut admin, PREFIX, "/oidc/callback").get(oidc_callback);
+    metrics::at(&mut admin, PREFIX, "/access").get(access_log);
+    metrics::at(&mut admin, PREFIX, "/audit").get(audit_trail);
+    metrics::at(&mut admin, PREFIX, "/webhooks")
+        .get(webhooks)
+        .post(create_webhook);
+    metrics::at(&mut admin, PREFIX, "/tokens").post(create_token);
+    metrics::at(&mut admin, PREFIX, "/tokens/share/:id").get(download_share);
+    metrics::at(&mut admin, PREFIX, "/tables").post(create_table);
+    metrics::at(&mut admin, PREFIX, "/tables/:id/description").post(describe_table);
+    metrics::at(&mut admin, PREFIX, "/schemas").post(create_schema);
+    metrics::at(&mut admin, PREFIX, "/shares").post(create_share);
+    app.at(PREFIX).nest(admin);
+}
+
+/**
+ * Return the CSRF token for the current session, which must be rendered into
+ * every form
+ */
+fn csrf_token(req: &Request<AppState<'_>>) -> String {
+    req.session()
+        .get::<String>(SESSION_CSRF)
+        .unwrap_or_default()
+}
+
+async fn login_form(req: Request<AppState<'_>>) -> Result<Body, tide::Error> {
+    req.state()
+        .render(
+            "login",
+            Some(&json!({
+                "csrf_token" : csrf_token(&req),
+                "oidc" : req.state().oidc.is_some(),
+            })),
+        )
+        .await
+}
+
+/**
+ * Mark the session as belonging to the given user, regenerating it to avoid
+ * session fixation
+ */
+fn start_session(
+    req: &mut Request<AppState<'_>>,
+    user: &str,
+    role: Role,
+) -> Result<(), tide::Error> {
+    // Nothing can be changed when the catalog comes from the configuration
+    let role = if req.state().config().catalog.is_read_only() {
+        Role::Viewer
+    } else {
+        role
+    };
+    info!("Admin user {} logged in with the {:?} role", user, role);
+    let session = req.session_mut();
+    session.regenerate();
+    session.insert(SESSION_USER, user)?;
+    session.insert(SESSION_ROLE, role)?;
+    // Rotate the CSRF token now that the session has been elevated
+    session.insert(SESSION_CSRF, Uuid::new_v4().to_hyphenated().to_string())?;
+    Ok(())
+}
+
+async fn login(mut req: Request<AppState<'_>>) -> Result<tide::Response, tide::Error> {
+    #[derive(Deserialize, Debug)]
+    struct LoginForm {
+        username: String,
+        password: String,
+    }
+
+    let form: LoginForm = req.body_form().await?;
+
+    if let Some(user) = req.state().authenticate(&form.username, &form.password) {
+        // The built-in users are always administrators
+        start_session(&mut req, &user.name, Role::Admin)?;
+        return Ok(tide::Redirect::new("/admin").into());
+    }
+
+    warn!("Failed admin login attempt for {}", form.username);
+    let body = req
+        .state()
+        .render(
+            "login",
+            Some(&json!({
+                "csrf_token" : csrf_token(&req),
+                "oidc" : req.state().oidc.is_some(),
+                "error" : "Invalid username or password",
+            })),
+        )
+        .await?;
+    Ok(tide::Response::builder(401).body(body).build())
+}
+
+async fn logout(mut req: Request<AppState<'_>>) -> Result<tide::Response, tide::Error> {
+    req.session_mut().destroy();
+    Ok(tide::Redirect::new("/admin/login").into())
+}
+
+/**
+ * GET /admin/oidc/login
+ *
+ * Start the OpenID Connect authorization code flow by sending the user to the
+ * identity provider
+ */
+async fn oidc_login(mut req: Request<AppState<'_>>) -> Result<tide::Response, tide::Error> {
+    let oidc = match &req.state().oidc {
+        Some(oidc) => oidc.clone(),
+        None => return Ok(tide::Response::builder(404).build()),
+    };
+
+    let state = Uuid::new_v4().to_hyphenated().to_string();
+    let nonce = Uuid::new_v4().to_hyphenated().to_string();
+    let url = oidc.authorize_url(&state, &nonce)?;
+
+    let session = req.session_mut();
+    session.insert(SESSION_OIDC_STATE, state)?;
+    session.insert(SESSION_OIDC_NONCE, nonce)?;
+
+    Ok(tide::Redirect::new(url).into())
+}
+
+/**
+ * GET /admin/oidc/callback
+ *
+ * Complete the OpenID Connect login once the identity provider has sent the
+ * user back with an authorization code
+ */
+async fn oidc_callback(mut req: Request<AppState<'_>>) -> Result<tide::Response, tide::Error> {
+    #[derive(Deserialize, Debug)]
+    struct Callback {
+        code: Option<String>,
+        state: Option<String>,
+        error: Option<String>,
+    }
+
+    let oidc = match &req.state().oidc {
+        Some(oidc) => oidc.clone(),
+        None => return Ok(tide::Response::builder(404).build()),
+    };
+    let callback: Callback = req.query()?;
+
+    if let Some(error) = callback.error {
+        warn!("The identity provider refused the login: {}", error);
+        return Ok(tide::Response::builder(401)
+            .body("The identity provider refused the login")
+            .build());
+    }
+
+    let expected_state = req.session().get::<String>(SESSION_OIDC_STATE);
+    let expected_nonce = req.session().get::<String>(SESSION_OIDC_NONCE);
+    req.session_mut().remove(SESSION_OIDC_STATE);
+    req.session_mut().remove(SESSION_OIDC_NONCE);
+
+    let code = match callback.code {
+        Some(code) if expected_state.is_some() && callback.state == expected_state => code,
+        _ => {
+            warn!("Rejecting OpenID Connect callback with an invalid state");
+            return Ok(tide::Response::builder(400)
+                .body("Invalid login state, please try again")
+                .build());
+        }
+    };
+
+    let claims = oidc.exchange(&code).await?;
+    if claims.nonce != expected_nonce {
+        warn!(
+            "Rejecting ID token for {} with an invalid nonce",
+            claims.sub
+        );
+        return Ok(tide::Response::builder(401).body("Invalid nonce").build());
+    }
+
+    match oidc.role_for(&claims) {
+        Some(role) => {
+            start_session(&mut req, claims.username(), role)?;
+            Ok(tide::Redirect::new("/admin").into())
+        }
+        None => {
+            warn!(
+                "{} is not a member of any riverbank admin group",
+                claims.username()
+            );
+            Ok(tide::Response::builder(403)
+                .body("You are not a member of any group allowed to use riverbank")
+                .build())
+        }
+    }
 }
 
 async fn index(req: Request<AppState<'_>>) -> Result<Body, tide::Error> {
-    let tables = Table::list_all(&req.state().db).await?;
-    let tokens = Token::list_all(&req.state().db).await?;
-    let schemas = Schema::list_all(&req.state().db).await?;
-    let shares = Share::list_all(&req.state().db).await?;
+    let statuses: std::collections::HashMap<Uuid, TableStatus> = match &req.state().db {
+        Some(db) => TableStatus::list_all(db)
+            .await?
+            .into_iter()
+            .map(|status| (status.table_id, status))
+            .collect(),
+        None => Default::default(),
+    };
+    let catalog = req.state().catalog();
+    let mut tables: Vec<serde_json::Value> = vec![];
+    for table in catalog.list_tables().await? {
+        let mut value = json!(table);
+        value["status"] = json!(statuses.get(&table.inner.id));
+        // The same figures as in the metadata, for tables which are cached
+        if let Some(snapshot) = req.state().table_cache.peek(&table.inner.location).await {
+            value["cached"] = json!({
+                "version" : snapshot.version,
+                "num_files" : snapshot.num_files,
+                "size" : snapshot.size,
+            });
+        }
+        tables.push(value);
+    }
+    let tokens = catalog.list_tokens().await?;
+    let schemas = catalog.list_schemas().await?;
+    let shares = catalog.list_shares().await?;
 
     req.state()
         .render(
             "admin",
-            Some(&json!({ "tables" : tables, "tokens" : tokens, "schemas" : schemas, "shares" : shares })),
+            Some(&json!({
+                "tables" : tables,
+                "tokens" : tokens,
+                "schemas" : schemas,
+                "shares" : shares,
+                "cache" : req.state().table_cache.stats(),
+                "read_only" : req.state().config().catalog.is_read_only(),
+                "database" : req.state().db.is_some(),
+                "user" : req.session().get::<String>(SESSION_USER),
+                "can_edit" : req.session().get::<Role>(SESSION_ROLE).map_or(false, |r| r.can_edit()),
+                "csrf_token" : csrf_token(&req),
+            })),
         )
         .await
 }
 
+/**
+ * The logged in user making changes through the console, which the catalog
+ * records in its audit trail along with each change
+ */
+fn actor(req: &Request<AppState<'_>>) -> Actor {
+    Actor {
+        name: req
+            .session()
+            .get::<String>(SESSION_USER)
+            .unwrap_or_else(|| "unknown".to_string()),
+        source_ip: req.remote().map(str::to_string),
+    }
+}
+
+/**
+ * GET /admin/audit
+ *
+ * Browse the history of changes made through the admin console, optionally
+ * for a single `object_type` and `object_id`
+ */
+async fn audit_trail(req: Request<AppState<'_>>) -> Result<Body, tide::Error> {
+    #[derive(Deserialize, Debug)]
+    struct AuditQuery {
+        object_type: Option<String>,
+        object_id: Option<Uuid>,
+    }
+
+    let query: AuditQuery = req.query()?;
+    let entries = req
+        .state()
+        .catalog()
+        .audit_trail(
+            query.object_type.as_deref().filter(|t| !t.is_empty()),
+            query.object_id.as_ref(),
+        )
+        .await?;
+
+    // Handlebars can't print nested objects, so show the values as JSON
+    let entries: Vec<serde_json::Value> = entries
+        .into_iter()
+        .map(|entry| {
+            let mut value = json!(entry);
+            value["old_value"] = json!(entry.old_value.map(|v| v.to_string()));
+            value["new_value"] = json!(entry.new_value.map(|v| v.to_string()));
+            value
+        })
+        .collect();
+
+    req.state()
+        .render(
+            "audit",
+            Some(&json!({
+                "entries" : entries,
+                "object_type" : query.object_type,
+                "object_id" : query.object_id,
+            })),
+        )
+        .await
+}
+
+/**
+ * GET /admin/access
+ *
+ * Browse the access_log, optionally filtered by token, share, table and date
+ * range. Passing `format=csv` or `format=json` exports the matching entries
+ */
+async fn access_log(req: Request<AppState<'_>>) -> Result<tide::Response, tide::Error> {
+    use chrono::{Duration, NaiveDate, TimeZone, Utc};
+
+    #[derive(Clone, Deserialize, Debug, Default, serde::Serialize)]
+    #[serde(default)]
+    struct AccessQuery {
+        token: String,
+        share: String,
+        table: String,
+        since: String,
+        until: String,
+        format: String,
+    }
+
+    fn non_empty(value: &str) -> Option<String> {
+        Some(value.trim().to_string()).filter(|v| !v.is_empty())
+    }
+
+    fn date(value: &str) -> Result<Option<NaiveDate>, tide::Error> {
+        match non_empty(value) {
+            Some(d) => Ok(Some(NaiveDate::parse_from_str(&d, "%Y-%m-%d").map_err(
+                |e| tide::Error::from_str(400, format!("Invalid date {}: {}", d, e)),
+            )?)),
+            None => Ok(None),
+        }
+    }
+
+    let query: AccessQuery = req.query()?;
+    let filter = AccessFilter {
+        token_name: non_empty(&query.token),
+        share_name: non_empty(&query.share),
+        table_name: non_empty(&query.table),
+        since: date(&query.since)?.map(|d| Utc.from_utc_date(&d).and_hms(0, 0, 0)),
+        // The until date is inclusive
+        until: date(&query.until)?
+            .map(|d| Utc.from_utc_date(&d).and_hms(0, 0, 0) + Duration::days(1)),
+        limit: if query.format.is_empty() {
+            500
+        } else {
+            100_000
+        },
+    };
+    let entries = AccessLog::list(&filter, req.state().database()?).await?;
+
+    match query.format.as_str() {
+        "csv" => Ok(tide::Response::builder(200)
+            .content_type("text/csv")
+            .header(
+                "Content-Disposition",
+                "attachment; filename=\"access_log.csv\"",
+            )
+            .body(access_log_csv(&entries))
+            .build()),
+        "json" => Ok(tide::Response::builder(200)
+            .header(
+                "Content-Disposition",
+                "attachment; filename=\"access_log.json\"",
+            )
+            .body(Body::from_json(&entries)?)
+            .build()),
+        "" => {
+            let export = serde_qs::to_string(&AccessQuery {
+                format: String::new(),
+                ..query.clone()
+            })?;
+            let body = req
+                .state()
+                .render(
+                    "access",
+                    Some(&json!({
+                        "entries" : entries,
+                        "filter" : {
+                            "token" : filter.token_name,
+                            "share" : filter.share_name,
+                            "table" : filter.table_name,
+                            "since" : non_empty(&query.since),
+                            "until" : non_empty(&query.until),
+                        },
+                        "export" : export,
+                    })),
+                )
+                .await?;
+            Ok(body.into())
+        }
+        other => Err(tide::Error::from_str(
+            400,
+            format!("Unsupported export format {}", other),
+        )),
+    }
+}
+
+/**
+ * Render the access_log entries as CSV for compliance reviews
+ */
+fn access_log_csv(entries: &[AccessLog]) -> String {
+    fn field<T: ToString>(value: &Option<T>) -> String {
+        value.as_ref().map(|v| v.to_string()).unwrap_or_default()
+    }
+
+    fn escape(value: String) -> String {
+        if value.contains(&[',', '"', '\n', '\r'][..]) {
+            format!("\"{}\"", value.replace('"', "\"\""))
+        } else {
+            value
+        }
+    }
+
+    let mut csv = String::from("created_at,token_id,token_name,operation,share,schema,table,table_version,predicate_hints,num_files,num_bytes,client_ip,user_agent\r\n");
+    for entry in entries {
+        let row = vec![
+            entry.created_at.to_rfc3339(),
+            entry.token_id.to_string(),
+            entry.token_name.clone(),
+            entry.operation.clone(),
+            field(&entry.share_name),
+            field(&entry.schema_name),
+            field(&entry.table_name),
+            field(&entry.table_version),
+            field(&entry.predicate_hints),
+            field(&entry.num_files),
+            field(&entry.num_bytes),
+            field(&entry.client_ip),
+            field(&entry.user_agent),
+        ];
+        csv.push_str(
+            &row.into_iter()
+                .map(escape)
+                .collect::<Vec<String>>()
+                .join(","),
+        );
+        csv.push_str("\r\n");
+    }
+    csv
+}
+
 async fn create_token(mut req: Request<AppState<'_>>) -> Result<tide::Response, tide::Error> {
     #[derive(Deserialize, Debug)]
     struct CreateForm {
@@ -63,21 +620,33 @@ async fn create_token(mut req: Request<AppState<'_>>) -> Result<tide::Response,
     let params = req.body_string().await?;
     if let Ok(create) = serde_qs::Config::new(5, false).deserialize_str::<CreateForm>(&params) {
         debug!("creating token with: {:?}", create);
-        let token = Token::generate(&create.name, &create.tables, &req.state().db).await?;
+        let token = req
+            .state()
+            .catalog()
+            .create_token(&actor(&req), &create.name, &create.tables)
+            .await?;
         debug!("created: {:?}", token);
     }
     Ok(tide::Redirect::new("/admin").into())
 }
 
 async fn download_share(req: Request<AppState<'_>>) -> Result<Body, tide::Error> {
+    // Viewers may browse the console, but must not walk away with credentials
+    if !req
+        .session()
+        .get::<Role>(SESSION_ROLE)
+        .map_or(false, |r| r.can_edit())
+    {
+        return Err(tide::Error::from_str(
+            403,
+            "Your role does not allow downloading share profiles",
+        ));
+    }
+
     let token_id: Uuid = Uuid::parse_str(req.param("id")?)?;
-    let token = Token::by_id(&token_id, &req.state().db).await?;
+    let token = req.state().catalog().token(&token_id).await?;
 
-    Ok(json!({
-        "shareCredentialsVersion" : 1,
-        "bearerToken" : token.token,
-        "endpoint" : std::env::var("RIVERBANK_URL").unwrap_or_else(|_| "http://localhost:8000/api/v1".to_string()),
-    }).into())
+    Ok(token.profile(&req.state().config().public_url).into())
 }
 
 async fn create_table(mut req: Request<AppState<'_>>) -> Result<tide::Response, tide::Error> {
@@ -89,13 +658,33 @@ async fn create_table(mut req: Request<AppState<'_>>) -> Result<tide::Response,
     }
 
     let create: CreateTable = req.body_form().await?;
-    Table::create(
-        &create.name,
-        &create.location,
-        &create.schema,
-        &req.state().db,
-    )
-    .await?;
+    req.state()
+        .catalog()
+        .create_table(&actor(&req), &create.name, &create.location, &create.schema)
+        .await?;
+
+    Ok(tide::Redirect::new("/admin").into())
+}
+
+/**
+ * POST /admin/tables/{id}/description
+ *
+ * Override the description of the Delta table which is handed to recipients,
+ * an empty description going back to the one in the Delta table
+ */
+async fn describe_table(mut req: Request<AppState<'_>>) -> Result<tide::Response, tide::Error> {
+    #[derive(Deserialize, Debug)]
+    struct DescribeTable {
+        description: String,
+    }
+
+    let id: Uuid = Uuid::parse_str(req.param("id")?)?;
+    let describe: DescribeTable = req.body_form().await?;
+    let description = Some(describe.description.trim()).filter(|d| !d.is_empty());
+    req.state()
+        .catalog()
+        .describe_table(&actor(&req), &id, description)
+        .await?;
 
     Ok(tide::Redirect::new("/admin").into())
 }
@@ -108,7 +697,10 @@ async fn create_schema(mut req: Request<AppState<'_>>) -> Result<tide::Response,
     }
 
     let create: CreateSchema = req.body_form().await?;
-    Schema::create(&create.name, &create.share, &req.state().db).await?;
+    req.state()
+        .catalog()
+        .create_schema(&actor(&req), &create.name, &create.share)
+        .await?;
 
     Ok(tide::Redirect::new("/admin").into())
 }
@@ -120,7 +712,146 @@ async fn create_share(mut req: Request<AppState<'_>>) -> Result<tide::Response,
     }
 
     let create: CreateShare = req.body_form().await?;
-    Share::create(&create.name, &req.state().db).await?;
+    req.state()
+        .catalog()
+        .create_share(&actor(&req), &create.name)
+        .await?;
 
     Ok(tide::Redirect::new("/admin").into())
 }
+
+/**
+ * GET /admin/webhooks
+ *
+ * List the webhooks of the recipients along with the log of deliveries,
+ * optionally only for a single `webhook`
+ */
+async fn webhooks(req: Request<AppState<'_>>) -> Result<Body, tide::Error> {
+    #[derive(Deserialize, Debug)]
+    struct WebhookQuery {
+        webhook: Option<Uuid>,
+    }
+
+    let query: WebhookQuery = req.query()?;
+    let db = req.state().database()?;
+    let webhooks = Webhook::list_all(db).await?;
+    let mut recipients: Vec<String> = req
+        .state()
+        .catalog()
+        .list_tokens()
+        .await?
+        .into_iter()
+        .map(|token| token.name)
+        .collect();
+    recipients.sort();
+    recipients.dedup();
+
+    let deliveries: Vec<serde_json::Value> = WebhookDelivery::list(query.webhook.as_ref(), db)
+        .await?
+        .into_iter()
+        .map(|delivery| {
+            let mut value = json!(delivery);
+            value["payload"] = json!(delivery.payload.to_string());
+            value
+        })
+        .collect();
+
+    req.state()
+        .render(
+            "webhooks",
+            Some(&json!({
+                "webhooks" : webhooks,
+                "recipients" : recipients,
+                "deliveries" : deliveries,
+                "webhook" : query.webhook,
+                "can_edit" : req.session().get::<Role>(SESSION_ROLE).map_or(false, |r| r.can_edit()),
+                "csrf_token" : csrf_token(&req),
+            })),
+        )
+        .await
+}
+
+async fn create_webhook(mut req: Request<AppState<'_>>) -> Result<tide::Response, tide::Error> {
+    #[derive(Deserialize, Debug)]
+    struct CreateWebhook {
+        recipient: String,
+        url: String,
+    }
+
+    let create: CreateWebhook = req.body_form().await?;
+    match tide::http::Url::parse(&create.url) {
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {}
+        _ => {
+            return Err(tide::Error::from_str(
+                400,
+                "The webhook URL must be an http or https URL",
+            ))
+        }
+    }
+
+    // Webhooks are kept in Postgres, and so is their trail
+    let mut tx = req.state().database()?.begin().await?;
+    let webhook = Webhook::create(&create.recipient, &create.url, &mut tx).await?;
+    // The secret itself must never end up in the audit trail
+    let entry = actor(&req).entry(
+        "create_webhook",
+        "webhook",
+        &webhook.id,
+        None,
+        Some(json!({
+            "recipient" : create.recipient,
+            "url" : create.url,
+        })),
+    );
+    AdminAudit::record(&entry, &mut tx).await?;
+    tx.commit().await?;
+
+    Ok(tide::Redirect::new("/admin/webhooks").into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_csrf_from_form() {
+        let params =
+            "name=test&tables%5B%5D=64947f1f-ef50-4280-bf6b-160c075cde43&csrf_token=abc-123";
+        assert_eq!(Some("abc-123".to_string()), csrf_from_form(params));
+    }
+
+    #[test]
+    fn test_csrf_from_form_missing() {
+        assert_eq!(None, csrf_from_form("name=test"));
+    }
+
+    #[test]
+    fn test_access_log_csv() {
+        use chrono::{TimeZone, Utc};
+
+        let entry = AccessLog {
+            id: Uuid::nil(),
+            token_id: Uuid::nil(),
+            token_name: "acme".to_string(),
+            operation: "QueryTable".to_string(),
+            share_name: Some("rtyler".to_string()),
+            schema_name: Some("samples".to_string()),
+            table_name: Some("covid19-nyt".to_string()),
+            table_version: Some(3),
+            predicate_hints: Some(json!({"predicateHints" : ["date = '2021-01-01'"]})),
+            num_files: Some(2),
+            num_bytes: Some(1024),
+            client_ip: None,
+            user_agent: Some("Delta-Sharing-Python/0.1.0, \"pandas\"".to_string()),
+            created_at: Utc.ymd(2021, 6, 1).and_hms(12, 0, 0),
+        };
+        let csv = access_log_csv(&[entry]);
+        let lines: Vec<&str> = csv.split("\r\n").collect();
+
+        assert_eq!(3, lines.len());
+        assert_eq!(
+            "2021-06-01T12:00:00+00:00,00000000-0000-0000-0000-000000000000,acme,QueryTable,rtyler,samples,covid19-nyt,3,\"{\"\"predicateHints\"\":[\"\"date = '2021-01-01'\"\"]}\",2,1024,,\"Delta-Sharing-Python/0.1.0, \"\"pandas\"\"\"",
+            lines[1]
+        );
+    }
+}
@@ -0,0 +1,139 @@
+/*
+ * The health routes are unauthenticated probes for orchestrators such as
+ * Kubernetes
+ */
+use serde::Serialize;
+use serde_json::json;
+use std::future::Future;
+use std::time::Duration;
+use tide::{Request, Response};
+
+use crate::metrics;
+use crate::models::pending_migrations;
+use crate::state::AppState;
+
+pub fn register(app: &mut tide::Server<AppState<'static>>) {
+    metrics::at(app, "", "/healthz").get(healthz);
+    metrics::at(app, "", "/readyz").get(readyz);
+}
+
+/**
+ * The outcome of a single readiness check
+ */
+#[derive(Debug, Serialize)]
+struct Check {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<serde_json::Value>,
+}
+
+impl Check {
+    fn passed(detail: Option<serde_json::Value>) -> Self {
+        Self {
+            ok: true,
+            error: None,
+            detail,
+        }
+    }
+
+    fn failed(error: String) -> Self {
+        Self {
+            ok: false,
+            error: Some(error),
+            detail: None,
+        }
+    }
+
+    /**
+     * Run the check, failing it when it doesn't complete within the timeout
+     */
+    async fn within<F>(timeout: Duration, check: F) -> Self
+    where
+        F: Future<Output = Check>,
+    {
+        async_std::future::timeout(timeout, check)
+            .await
+            .unwrap_or_else(|_| {
+                Check::failed(format!("Timed out after {} seconds", timeout.as_secs()))
+            })
+    }
+}
+
+/**
+ * GET /healthz
+ *
+ * The process is up and serving requests
+ */
+async fn healthz(_req: Request<AppState<'_>>) -> tide::Result {
+    Ok(Response::builder(200)
+        .body(json!({"status" : "ok"}))
+        .build())
+}
+
+/**
+ * GET /readyz
+ *
+ * Riverbank can serve the sharing API: the database, if there is one, is
+ * reachable, all the migrations have been applied and the canary table, if
+ * configured, can be read
+ */
+async fn readyz(req: Request<AppState<'_>>) -> tide::Result {
+    let state = req.state();
+    let config = state.config();
+    let timeout = Duration::from_secs(config.health.timeout_seconds);
+
+    let mut ready = true;
+    let mut checks = json!({});
+
+    // Without a database there is nothing to connect to or migrate
+    if let Some(db) = &state.db {
+        let database = Check::within(timeout, async {
+            match sqlx::query!("SELECT 1 AS ok").fetch_one(db).await {
+                Ok(_) => Check::passed(Some(json!({
+                    "connections" : db.size(),
+                    "idle" : db.num_idle(),
+                }))),
+                Err(e) => Check::failed(e.to_string()),
+            }
+        })
+        .await;
+
+        let migrations = Check::within(timeout, async {
+            match pending_migrations(db).await {
+                Ok(pending) if pending.is_empty() => Check::passed(None),
+                Ok(pending) => Check {
+                    ok: false,
+                    error: Some("Not all migrations have been applied".to_string()),
+                    detail: Some(json!({ "pending" : pending })),
+                },
+                Err(e) => Check::failed(e.to_string()),
+            }
+        })
+        .await;
+
+        ready = database.ok && migrations.ok;
+        checks["database"] = json!(database);
+        checks["migrations"] = json!(migrations);
+    }
+
+    if let Some(location) = &config.health.canary_table {
+        let canary = Check::within(timeout, async {
+            match state.table_cache.load(location).await {
+                Ok(snapshot) => Check::passed(Some(json!({ "version" : snapshot.version }))),
+                Err(e) => Check::failed(e.to_string()),
+            }
+        })
+        .await;
+        ready = ready && canary.ok;
+        checks["canary"] = json!(canary);
+    }
+
+    Ok(Response::builder(if ready { 200 } else { 503 })
+        .body(json!({
+            "status" : if ready { "ok" } else { "unavailable" },
+            "checks" : checks,
+        }))
+        .build())
+}
@@ -6,4 +6,5 @@
  */
 
 pub mod admin;
+pub mod health;
 pub mod v1;
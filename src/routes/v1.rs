@@ -1,8 +1,15 @@
-use serde::Serialize;
+use log::*;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
+use sqlx::PgPool;
+use tide::http::Method;
 use tide::{Body, Request, Response};
 
-use crate::state::{AppState, Tokened};
+use crate::capabilities::{self, Capabilities, ResponseFormat};
+use crate::config::Limits;
+use crate::metrics;
+use crate::models::{AccessEntry, AccessLog, PresignError, Table, UsageCounter};
+use crate::state::{AppState, NoActiveToken, Tokened};
 
 #[derive(Default)]
 struct RequireTokenMiddleware;
@@ -15,30 +22,209 @@ impl<AppState: Clone + Send + Sync + 'static> tide::Middleware<AppState>
         if let Some(_token) = req.ext::<Tokened>() {
             Ok(next.run(req).await)
         } else {
+            crate::metrics::TOKEN_AUTH_FAILURES.inc();
             Ok(Response::builder(401).body("Not authenticated").build())
         }
     }
 }
 
+/**
+ * The number of bytes of data files a query response handed out, which the
+ * RateLimitMiddleware counts against the daily quotas
+ */
+#[derive(Clone, Copy, Debug)]
+struct ServedBytes(i64);
+
+/**
+ * Enforce the configured per-token and per-recipient rate limits and daily
+ * quotas, with the counters kept in Postgres so that they survive restarts
+ */
+#[derive(Default)]
+struct RateLimitMiddleware;
+
+#[tide::utils::async_trait]
+impl tide::Middleware<AppState<'static>> for RateLimitMiddleware {
+    async fn handle(
+        &self,
+        req: Request<AppState<'static>>,
+        next: tide::Next<'_, AppState<'static>>,
+    ) -> tide::Result {
+        let config = req.state().config().rate_limits.clone();
+        let db = match &req.state().db {
+            Some(db) if !config.is_empty() => db.clone(),
+            _ => return Ok(next.run(req).await),
+        };
+
+        let tokened = req.ext::<Tokened>().unwrap();
+        let subjects = vec![
+            (format!("token:{}", tokened.id), config.per_token.clone()),
+            (
+                format!("recipient:{}", tokened.name),
+                config.for_recipient(&tokened.name).clone(),
+            ),
+        ];
+        let is_query = req.method() == Method::Post && req.url().path().ends_with("/query");
+        let mut counted = vec![];
+
+        for (subject, limits) in subjects.iter() {
+            // Failing to count is logged rather than failing the request
+            match exceeded(subject, limits, is_query, &mut counted, &db).await {
+                Ok(Some(retry_after)) => {
+                    debug!("Rate limit exceeded for {}", subject);
+                    // Only the requests which are let through use up the limits
+                    for (counter, requests, queries) in counted.iter() {
+                        if let Err(e) = counter.take_back(*requests, *queries, &db).await {
+                            error!("Failed to uncount usage for {}: {}", counter.subject, e);
+                        }
+                    }
+                    return Ok(Response::builder(429)
+                        .header("Retry-After", retry_after.to_string())
+                        .body("Rate limit exceeded")
+                        .build());
+                }
+                Ok(None) => {}
+                Err(e) => error!("Failed to count usage for {}: {}", subject, e),
+            }
+        }
+
+        let response = next.run(req).await;
+
+        if let Some(ServedBytes(bytes)) = response.ext::<ServedBytes>() {
+            for (subject, limits) in subjects.iter() {
+                if limits.daily_bytes.is_some() {
+                    if let Err(e) = UsageCounter::add(subject, "day", 0, 0, *bytes, &db).await {
+                        error!("Failed to count served bytes for {}: {}", subject, e);
+                    }
+                }
+            }
+        }
+        Ok(response)
+    }
+}
+
+/**
+ * Count the request against the subject's limits, returning the number of
+ * seconds the client should wait if any of them has been exceeded. The
+ * counters added to are pushed onto `counted` with the requests and queries
+ * added, for taking them back should the request be rejected.
+ *
+ * The bytes quota can only be checked before a query is run, so the query
+ * which crosses it is still answered
+ */
+async fn exceeded(
+    subject: &str,
+    limits: &Limits,
+    is_query: bool,
+    counted: &mut Vec<(UsageCounter, i64, i64)>,
+    db: &PgPool,
+) -> Result<Option<i64>, sqlx::Error> {
+    if let Some(max) = limits.requests_per_minute {
+        let counter = UsageCounter::add(subject, "minute", 1, 0, 0, db).await?;
+        counted.push((counter.clone(), 1, 0));
+        if counter.requests == 1 {
+            UsageCounter::prune(subject, "minute", db).await?;
+        }
+        if counter.requests > max {
+            return Ok(Some(counter.retry_after()));
+        }
+    }
+
+    if is_query && (limits.daily_queries.is_some() || limits.daily_bytes.is_some()) {
+        let counter = UsageCounter::add(subject, "day", 0, 1, 0, db).await?;
+        counted.push((counter.clone(), 0, 1));
+        if counter.queries == 1 {
+            UsageCounter::prune(subject, "day", db).await?;
+        }
+        let too_many_queries = limits
+            .daily_queries
+            .map_or(false, |max| counter.queries > max);
+        let too_many_bytes = limits.daily_bytes.map_or(false, |max| counter.bytes >= max);
+        if too_many_queries || too_many_bytes {
+            return Ok(Some(counter.retry_after()));
+        }
+    }
+    Ok(None)
+}
+
+/// Where the routes are nested on the server
+const PREFIX: &str = "/api/v1";
+
 pub fn register(app: &mut tide::Server<AppState<'static>>) {
     let mut api = tide::with_state(app.state().clone());
 
+    // Explain why a valid JWT was refused, as the error would otherwise leave
+    // the response empty
+    api.with(tide::utils::After(|mut res: Response| async move {
+        if let Some(e) = res.downcast_error::<NoActiveToken>() {
+            let body = e.to_string();
+            res.set_body(body);
+        }
+        Ok(res)
+    }));
     api.with(tide_http_auth::Authentication::new(
         tide_http_auth::BearerAuthScheme::default(),
     ));
     api.with(RequireTokenMiddleware {});
+    api.with(RateLimitMiddleware {});
+
+    metrics::at(&mut api, PREFIX, "/shares").get(list_shares);
+    metrics::at(&mut api, PREFIX, "/shares/:share").get(get_share);
+    metrics::at(&mut api, PREFIX, "/shares/:share/all-tables").get(list_all_tables);
+    metrics::at(&mut api, PREFIX, "/shares/:share/schemas").get(list_schemas);
+    metrics::at(&mut api, PREFIX, "/shares/:share/schemas/:schema/tables").get(list_tables);
+    metrics::at(
+        &mut api,
+        PREFIX,
+        "/shares/:share/schemas/:schema/tables/:table",
+    )
+    .get(latest_version);
+    metrics::at(
+        &mut api,
+        PREFIX,
+        "/shares/:share/schemas/:schema/tables/:table/metadata",
+    )
+    .get(table_metadata);
+    metrics::at(
+        &mut api,
+        PREFIX,
+        "/shares/:share/schemas/:schema/tables/:table/query",
+    )
+    .post(query);
+    app.at(PREFIX).nest(api);
+}
+
+/**
+ * Start an access_log entry for the request, with the recipient's token and
+ * client details filled in
+ */
+fn access_entry(req: &Request<AppState<'_>>, operation: &'static str) -> AccessEntry {
+    AccessEntry {
+        token_id: req.ext::<Tokened>().unwrap().id,
+        operation,
+        share_name: req.param("share").ok().map(str::to_string),
+        schema_name: req.param("schema").ok().map(str::to_string),
+        table_name: req.param("table").ok().map(str::to_string),
+        table_version: None,
+        predicate_hints: None,
+        num_files: None,
+        num_bytes: None,
+        client_ip: req.remote().map(str::to_string),
+        user_agent: req.header("User-Agent").map(|ua| ua.last().to_string()),
+    }
+}
 
-    api.at("/shares").get(list_shares);
-    api.at("/shares/:share/schemas").get(list_schemas);
-    api.at("/shares/:share/schemas/:schema/tables")
-        .get(list_tables);
-    api.at("/shares/:share/schemas/:schema/tables/:table")
-        .get(latest_version);
-    api.at("/shares/:share/schemas/:schema/tables/:table/metadata")
-        .get(table_metadata);
-    api.at("/shares/:share/schemas/:schema/tables/:table/query")
-        .post(query);
-    app.at("/api/v1").nest(api);
+/**
+ * Write the entry to the access_log. Failing to do so is logged rather than
+ * failing the recipient's request
+ */
+async fn record_access(req: &Request<AppState<'_>>, entry: AccessEntry) {
+    let db = match &req.state().db {
+        Some(db) if req.state().config().catalog.is_postgres() => db,
+        _ => return,
+    };
+    if let Err(e) = AccessLog::record(&entry, db).await {
+        error!("Failed to record access {:?}: {}", entry, e);
+    }
 }
 
 /**
@@ -46,37 +232,68 @@ pub fn register(app: &mut tide::Server<AppState<'static>>) {
  * operationId: ListShares
  */
 async fn list_shares(req: Request<AppState<'_>>) -> Result<Body, tide::Error> {
-    use crate::models::Share;
-
-    let db = &req.state().db;
-    let mut response = PaginatedResponse::default();
+    let catalog = req.state().catalog();
     let tokened = req.ext::<Tokened>().unwrap();
-    for share in Share::list_by_token(&tokened.id, db).await? {
-        response.items.push(json!({"name" : &share.name}));
-    }
-
+    let shares = catalog.shares_for_token(&tokened.id).await?;
+    let response = PaginatedResponse::paginate(
+        &req,
+        shares
+            .iter()
+            .map(|share| json!({"name" : &share.name, "id" : &share.id}))
+            .collect(),
+    )?;
+
+    record_access(&req, access_entry(&req, "ListShares")).await;
     Body::from_json(&response)
 }
 
+/**
+ * GET /api/v1/shares/{share}
+ * operationId: GetShare
+ */
+async fn get_share(req: Request<AppState<'_>>) -> Result<Body, tide::Error> {
+    let named_share = req.param("share")?;
+    let tokened = req.ext::<Tokened>().unwrap();
+    let share = req
+        .state()
+        .catalog()
+        .find_share(&named_share, &tokened.id)
+        .await
+        .map_err(not_found)?;
+
+    record_access(&req, access_entry(&req, "GetShare")).await;
+    Body::from_json(&json!({"share" : {"name" : &share.name, "id" : &share.id}}))
+}
+
 /**
  * GET /api/v1/shares/{share}/schemas
  * operationId: ListSchemas
  */
 async fn list_schemas(req: Request<AppState<'_>>) -> Result<Body, tide::Error> {
-    use crate::models::*;
-
-    let db = &req.state().db;
+    let catalog = req.state().catalog();
     let named_share = req.param("share")?;
-    let mut response = PaginatedResponse::default();
     let tokened = req.ext::<Tokened>().unwrap();
-
-    for schema in Schema::list_by_token(&named_share, &tokened.id, &db).await? {
-        response.items.push(json!({
-            "name": &schema.name,
-            "share" : &schema.share_name,
-        }));
-    }
-
+    // A share without any table granted to the token is as good as missing
+    catalog
+        .find_share(&named_share, &tokened.id)
+        .await
+        .map_err(not_found)?;
+    let schemas = catalog.schemas_for_token(&named_share, &tokened.id).await?;
+    let response = PaginatedResponse::paginate(
+        &req,
+        schemas
+            .iter()
+            .map(|schema| {
+                json!({
+                    "name": &schema.name,
+                    "share" : &schema.share_name,
+                    "shareId" : &schema.share_id,
+                })
+            })
+            .collect(),
+    )?;
+
+    record_access(&req, access_entry(&req, "ListSchemas")).await;
     Body::from_json(&response)
 }
 
@@ -85,44 +302,177 @@ async fn list_schemas(req: Request<AppState<'_>>) -> Result<Body, tide::Error> {
  * operationId: ListTables
  */
 async fn list_tables(req: Request<AppState<'_>>) -> Result<Body, tide::Error> {
-    use crate::models::Table;
-
     let named_share = req.param("share")?;
     let named_schema = req.param("schema")?;
-    let db = &req.state().db;
-    let mut tables = PaginatedResponse::default();
+    let catalog = req.state().catalog();
     let tokened = req.ext::<Tokened>().unwrap();
-
-    for table in Table::list_by_token(&named_share, &named_schema, &tokened.id, db).await? {
-        tables.items.push(json!({
-            "name" : table.name(),
-            "share" : table.share(),
-            "schema" : table.schema(),
-        }));
+    let tables = catalog
+        .tables_for_token(&named_share, &named_schema, &tokened.id)
+        .await
+        .map_err(not_found)?;
+    // A schema without any table granted to the token is as good as missing
+    if tables.is_empty() {
+        return Err(not_found(sqlx::Error::RowNotFound));
     }
+    let tables = PaginatedResponse::paginate(&req, tables.iter().map(table_item).collect())?;
+
+    record_access(&req, access_entry(&req, "ListTables")).await;
+    Body::from_json(&tables)
+}
 
+/**
+ * GET /api/v1/shares/{share}/all-tables
+ * operationId: ListAllTables
+ */
+async fn list_all_tables(req: Request<AppState<'_>>) -> Result<Body, tide::Error> {
+    let named_share = req.param("share")?;
+    let tokened = req.ext::<Tokened>().unwrap();
+    let tables = req
+        .state()
+        .catalog()
+        .all_tables_for_token(&named_share, &tokened.id)
+        .await
+        .map_err(not_found)?;
+    let tables = PaginatedResponse::paginate(&req, tables.iter().map(table_item).collect())?;
+
+    record_access(&req, access_entry(&req, "ListAllTables")).await;
     Body::from_json(&tables)
 }
 
+/**
+ * The capabilities which the client declared in the
+ * `delta-sharing-capabilities` header
+ */
+fn capabilities(req: &Request<AppState<'_>>) -> Capabilities {
+    req.header(capabilities::HEADER)
+        .map(|values| {
+            let values: Vec<&str> = values.iter().map(|value| value.as_str()).collect();
+            Capabilities::parse(&values.join(";"))
+        })
+        .unwrap_or_default()
+}
+
+/**
+ * Refuse to serve a table to a client which did not declare every reader
+ * feature the table requires, since it would misread the data
+ */
+fn check_features(table: &Table, capabilities: &Capabilities) -> Result<(), tide::Error> {
+    let required = table.reader_features()?;
+    let unsupported: Vec<&str> = required
+        .iter()
+        .map(String::as_str)
+        .filter(|feature| capabilities::UNSUPPORTED.contains(feature))
+        .collect();
+    if !unsupported.is_empty() {
+        return Err(tide::Error::from_str(
+            400,
+            format!(
+                "The table {}.{}.{} requires the reader features {}, which riverbank cannot serve yet",
+                table.share(),
+                table.schema(),
+                table.name(),
+                unsupported.join(", "),
+            ),
+        ));
+    }
+    let missing = capabilities.missing(required);
+    if !missing.is_empty() {
+        return Err(tide::Error::from_str(
+            400,
+            format!(
+                "The table {}.{}.{} requires the reader features {}, which the client did not declare in the {} header",
+                table.share(),
+                table.schema(),
+                table.name(),
+                missing.join(", "),
+                capabilities::HEADER
+            ),
+        ));
+    }
+    // The parquet format has no room for deletion vectors and the like
+    if !required.is_empty() && capabilities.response_format() == ResponseFormat::Parquet {
+        return Err(tide::Error::from_str(
+            400,
+            format!(
+                "The table {}.{}.{} requires the reader features {}, which can only be read with responseformat=delta",
+                table.share(),
+                table.schema(),
+                table.name(),
+                required.join(", "),
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/**
+ * The `delta-sharing-capabilities` response header, telling the client the
+ * format the response is in
+ */
+fn response_format_header(format: ResponseFormat) -> String {
+    format!("responseformat={}", format.as_str())
+}
+
+fn table_item(table: &Table) -> serde_json::Value {
+    json!({
+        "name" : table.name(),
+        "share" : table.share(),
+        "schema" : table.schema(),
+        "shareId" : table.share_id(),
+        "id" : table.id(),
+    })
+}
+
+/**
+ * Missing credentials are reported as a temporary outage, which clients may
+ * retry, rather than as a fault of riverbank
+ */
+fn presign_error(e: PresignError) -> tide::Error {
+    match e {
+        PresignError::Credentials(_) => {
+            error!("Cannot presign the files of tables: {}", e);
+            tide::Error::from_str(503, "The files of the table cannot be handed out right now")
+        }
+        e => e.into(),
+    }
+}
+
+/**
+ * Answer with a 404 for shares and tables which do not exist or are not
+ * granted to the token, rather than telling the two apart
+ */
+fn not_found(e: sqlx::Error) -> tide::Error {
+    match e {
+        sqlx::Error::RowNotFound => tide::Error::from_str(404, "Not found"),
+        e => e.into(),
+    }
+}
+
 /**
  * HEAD /shares/{share}/schemas/{schema}/tables/{table}
  * operationId: GetTableVersion
  */
 async fn latest_version(req: Request<AppState<'_>>) -> Result<tide::Response, tide::Error> {
-    use crate::models::Table;
-
-    let db = &req.state().db;
     let named_share = req.param("share")?;
     let named_schema = req.param("schema")?;
     let named_table = req.param("table")?;
     let tokened = req.ext::<Tokened>().unwrap();
 
-    // TODO: handle 404
-    let mut table = Table::find(&named_share, &named_schema, &named_table, &tokened.id, db).await?;
-    table.load_delta().await?;
+    let mut table = req
+        .state()
+        .catalog()
+        .find_table(&named_share, &named_schema, &named_table, &tokened.id)
+        .await
+        .map_err(not_found)?;
+    table.load_delta(&req.state().table_cache).await?;
+    let version = table.delta_version()?;
+
+    let mut entry = access_entry(&req, "GetTableVersion");
+    entry.table_version = Some(version);
+    record_access(&req, entry).await;
 
     return Ok(tide::Response::builder(200)
-        .header("Delta-Table-Version", table.delta_version()?)
+        .header("Delta-Table-Version", version.to_string())
         .build());
     //Ok(tide::Response::builder(404).build())
 }
@@ -136,23 +486,42 @@ async fn latest_version(req: Request<AppState<'_>>) -> Result<tide::Response, ti
  * lines of content that the client is expecting.
  */
 async fn table_metadata(req: Request<AppState<'_>>) -> Result<tide::Response, tide::Error> {
-    use crate::models::Table;
-
     let named_share = req.param("share")?;
     let named_schema = req.param("schema")?;
     let named_table = req.param("table")?;
     let tokened = req.ext::<Tokened>().unwrap();
     // TODO 404
 
-    let db = &req.state().db;
-    let mut table = Table::find(&named_share, &named_schema, &named_table, &tokened.id, db).await?;
-    table.load_delta().await?;
-
-    let metadata = json!({"metaData" : table.metadata()?});
-    let protocol = json!({"protocol" : table.protocol()?});
+    let mut table = req
+        .state()
+        .catalog()
+        .find_table(&named_share, &named_schema, &named_table, &tokened.id)
+        .await
+        .map_err(not_found)?;
+    table.load_delta(&req.state().table_cache).await?;
+    let capabilities = capabilities(&req);
+    check_features(&table, &capabilities)?;
+
+    let format = capabilities.response_format();
+    let (protocol, metadata) = match format {
+        ResponseFormat::Parquet => (
+            json!({"protocol" : table.protocol()?}),
+            json!({"metaData" : table.metadata(req.state().config().metadata.include_stats)?}),
+        ),
+        ResponseFormat::Delta => (
+            json!({"protocol" : table.delta_protocol()?}),
+            json!({"metaData" : table.delta_metadata()?}),
+        ),
+    };
+    let version = table.delta_version()?;
+
+    let mut entry = access_entry(&req, "GetTableMetadata");
+    entry.table_version = Some(version);
+    record_access(&req, entry).await;
 
     return Ok(tide::Response::builder(200)
-        .header("Delta-Table-Version", table.delta_version()?)
+        .header("Delta-Table-Version", version.to_string())
+        .header(capabilities::HEADER, response_format_header(format))
         // Really gross hacking the "streaming JSON" into place
         .body(format!("{}\n{}", protocol, metadata))
         .build());
@@ -163,30 +532,77 @@ async fn table_metadata(req: Request<AppState<'_>>) -> Result<tide::Response, ti
  * POST /shares/{share}/schemas/{schema}/tables/{table}/query
  * operationId: QueryTable
  */
-async fn query(req: Request<AppState<'_>>) -> Result<tide::Response, tide::Error> {
-    use crate::models::Table;
+async fn query(mut req: Request<AppState<'_>>) -> Result<tide::Response, tide::Error> {
+    #[derive(Debug, Default, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct QueryRequest {
+        predicate_hints: Option<Vec<String>>,
+        limit_hint: Option<i64>,
+    }
+
+    // The body is optional, and the hints are only hints, so anything which
+    // cannot be understood is ignored
+    let hints: QueryRequest = req.body_json().await.unwrap_or_default();
 
     let named_share = req.param("share")?;
     let named_schema = req.param("schema")?;
     let named_table = req.param("table")?;
     let tokened = req.ext::<Tokened>().unwrap();
 
-    let db = &req.state().db;
-    let mut table = Table::find(&named_share, &named_schema, &named_table, &tokened.id, db).await?;
-    table.load_delta().await?;
-
-    let metadata = json!({"metaData" : table.metadata()?});
-    let protocol = json!({"protocol" : table.protocol()?});
+    let mut table = req
+        .state()
+        .catalog()
+        .find_table(&named_share, &named_schema, &named_table, &tokened.id)
+        .await
+        .map_err(not_found)?;
+    table.load_delta(&req.state().table_cache).await?;
+    let capabilities = capabilities(&req);
+    check_features(&table, &capabilities)?;
+
+    let config = req.state().config();
+    let storage = &config.storage;
+    let format = capabilities.response_format();
+    let (protocol, metadata, files) = match format {
+        // The size of every file follows in the response itself
+        ResponseFormat::Parquet => (
+            json!({"protocol" : table.protocol()?}),
+            json!({"metaData" : table.metadata(false)?}),
+            table.urls(storage).await.map_err(presign_error)?,
+        ),
+        ResponseFormat::Delta => (
+            json!({"protocol" : table.delta_protocol()?}),
+            json!({"metaData" : table.delta_metadata()?}),
+            table.actions(storage).await.map_err(presign_error)?,
+        ),
+    };
 
     let mut response = vec![protocol.to_string(), metadata.to_string()];
-    for url in table.urls().await? {
-        response.push(url.to_string());
+    let mut num_bytes = 0;
+    for file in files.iter() {
+        let size = match format {
+            ResponseFormat::Parquet => &file["file"]["size"],
+            ResponseFormat::Delta => &file["file"]["deltaSingleAction"]["add"]["size"],
+        };
+        num_bytes += size.as_i64().unwrap_or(0);
+        response.push(file.to_string());
     }
-    return Ok(tide::Response::builder(200)
-        .header("Delta-Table-Version", table.delta_version()?)
+    let version = table.delta_version()?;
+
+    let mut entry = access_entry(&req, "QueryTable");
+    entry.table_version = Some(version);
+    entry.predicate_hints = Some(serde_json::to_value(&hints)?);
+    entry.num_files = Some(files.len() as i64);
+    entry.num_bytes = Some(num_bytes);
+    record_access(&req, entry).await;
+
+    let mut response = tide::Response::builder(200)
+        .header("Delta-Table-Version", version.to_string())
+        .header(capabilities::HEADER, response_format_header(format))
         // Really gross hacking the "streaming JSON" into place
         .body(response.join("\n"))
-        .build());
+        .build();
+    response.insert_ext(ServedBytes(num_bytes));
+    return Ok(response);
     //Ok(tide::Response::builder(404).build())
 }
 
@@ -205,3 +621,96 @@ impl Default for PaginatedResponse {
         }
     }
 }
+
+/// The largest page which is handed out, whatever the client asks for
+const MAX_RESULTS: usize = 1000;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Pagination {
+    max_results: Option<usize>,
+    page_token: Option<String>,
+}
+
+impl PaginatedResponse {
+    /**
+     * Return the page of the items selected by the `maxResults` and
+     * `pageToken` query parameters. Without `maxResults` every item after the
+     * page token is returned.
+     *
+     * The page token is the offset of the next item, which is good enough
+     * since every catalog sorts the listings by name and then id
+     */
+    fn paginate<State>(
+        req: &Request<State>,
+        items: Vec<serde_json::Value>,
+    ) -> Result<Self, tide::Error> {
+        let pagination: Pagination = req
+            .query()
+            .map_err(|_| tide::Error::from_str(400, "Invalid maxResults or pageToken"))?;
+        Self::page(pagination, items)
+    }
+
+    fn page(pagination: Pagination, items: Vec<serde_json::Value>) -> Result<Self, tide::Error> {
+        let start = match &pagination.page_token {
+            Some(token) => token
+                .parse::<usize>()
+                .ok()
+                .filter(|start| *start <= items.len())
+                .ok_or_else(|| tide::Error::from_str(400, "Invalid pageToken"))?,
+            None => 0,
+        };
+        let max = match pagination.max_results {
+            Some(0) => return Err(tide::Error::from_str(400, "maxResults must be positive")),
+            Some(max) => max.min(MAX_RESULTS),
+            None => items.len(),
+        };
+
+        let end = items.len().min(start + max);
+        Ok(Self {
+            next_page_token: Some(end)
+                .filter(|end| *end < items.len())
+                .map(|end| end.to_string()),
+            items: items.into_iter().skip(start).take(end - start).collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(max_results: Option<usize>, page_token: Option<&str>) -> PaginatedResponse {
+        let items = (0..5).map(|i| json!(i)).collect();
+        PaginatedResponse::page(
+            Pagination {
+                max_results,
+                page_token: page_token.map(str::to_string),
+            },
+            items,
+        )
+        .expect("Failed to paginate")
+    }
+
+    #[test]
+    fn test_paginate() {
+        let all = page(None, None);
+        assert_eq!(5, all.items.len());
+        assert_eq!(None, all.next_page_token);
+
+        let first = page(Some(2), None);
+        assert_eq!(vec![json!(0), json!(1)], first.items);
+        assert_eq!(Some("2".to_string()), first.next_page_token);
+
+        let last = page(Some(2), Some("4"));
+        assert_eq!(vec![json!(4)], last.items);
+        assert_eq!(None, last.next_page_token);
+
+        let items = (0..5).map(|i| json!(i)).collect();
+        let invalid = Pagination {
+            max_results: None,
+            page_token: Some("nope".to_string()),
+        };
+        assert!(PaginatedResponse::page(invalid, items).is_err());
+    }
+}
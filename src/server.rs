@@ -0,0 +1,445 @@
+/*
+ * The server module accepts connections for the tide app, optionally
+ * terminating TLS, and drains open connections before shutting down
+ */
+use async_std::channel::Receiver;
+use async_std::net::{SocketAddr, TcpListener, TcpStream};
+use async_std::task;
+use futures_lite::FutureExt;
+use futures_rustls::rustls::internal::pemfile;
+use futures_rustls::rustls::{NoClientAuth, PrivateKey, ServerConfig};
+use futures_rustls::{server::TlsStream, TlsAcceptor};
+use log::*;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use crate::config::{ServerConfig as Config, TlsConfig};
+
+/**
+ * The signals the server acts on
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Signal {
+    /// SIGHUP, reload the configuration and the TLS certificate and key
+    Reload,
+    /// SIGTERM or SIGINT, stop accepting connections and drain the open ones
+    Shutdown,
+}
+
+/**
+ * Forward the signals of the process from a dedicated thread
+ */
+pub fn signals() -> io::Result<Receiver<Signal>> {
+    use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+
+    let mut signals = signal_hook::iterator::Signals::new(&[SIGHUP, SIGINT, SIGTERM])?;
+    let (sender, receiver) = async_std::channel::unbounded();
+
+    std::thread::spawn(move || {
+        for signal in signals.forever() {
+            let signal = match signal {
+                SIGHUP => Signal::Reload,
+                _ => Signal::Shutdown,
+            };
+            if sender.try_send(signal).is_err() {
+                return;
+            }
+        }
+    });
+    Ok(receiver)
+}
+
+/**
+ * Forward every signal to two receivers, so that something besides the server
+ * can act on them too
+ */
+pub fn tee(signals: Receiver<Signal>) -> (Receiver<Signal>, Receiver<Signal>) {
+    let (first, first_receiver) = async_std::channel::unbounded();
+    let (second, second_receiver) = async_std::channel::unbounded();
+
+    task::spawn(async move {
+        while let Ok(signal) = signals.recv().await {
+            // Either side may have stopped listening already
+            let _ = first.send(signal).await;
+            let _ = second.send(signal).await;
+        }
+    });
+    (first_receiver, second_receiver)
+}
+
+/**
+ * Load the certificate chain and private key from their PEM files
+ */
+fn load_tls(config: &TlsConfig) -> io::Result<Arc<ServerConfig>> {
+    let invalid = |what: &str, path: &Path| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("No valid {} found in {}", what, path.display()),
+        )
+    };
+    let read_keys = |parse: fn(&mut dyn io::BufRead) -> Result<Vec<PrivateKey>, ()>| {
+        let mut reader = BufReader::new(File::open(&config.key_path)?);
+        parse(&mut reader).map_err(|_| invalid("private key", &config.key_path))
+    };
+
+    let certs = pemfile::certs(&mut BufReader::new(File::open(&config.cert_path)?))
+        .map_err(|_| invalid("certificates", &config.cert_path))?;
+    if certs.is_empty() {
+        return Err(invalid("certificates", &config.cert_path));
+    }
+
+    let mut keys = read_keys(pemfile::pkcs8_private_keys)?;
+    if keys.is_empty() {
+        keys = read_keys(pemfile::rsa_private_keys)?;
+    }
+    let key = keys
+        .into_iter()
+        .next()
+        .ok_or_else(|| invalid("private key", &config.key_path))?;
+
+    let mut tls = ServerConfig::new(NoClientAuth::new());
+    tls.set_single_cert(certs, key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(Arc::new(tls))
+}
+
+/**
+ * async-h1 needs a connection it can clone, which a TlsStream is not
+ */
+#[derive(Clone)]
+struct TlsConnection(Arc<Mutex<TlsStream<TcpStream>>>);
+
+impl async_std::io::Read for TlsConnection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.0.lock().unwrap()).poll_read(cx, buf)
+    }
+}
+
+impl async_std::io::Write for TlsConnection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.0.lock().unwrap()).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.0.lock().unwrap()).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.0.lock().unwrap()).poll_close(cx)
+    }
+}
+
+/// How often a connection checks whether it has become idle while draining
+const IDLE_CHECK: Duration = Duration::from_millis(50);
+
+/**
+ * Whether a connection is idle, waiting for the client to send another request
+ * on a keep-alive connection
+ */
+#[derive(Clone, Default)]
+struct Activity {
+    /// The requests being handled
+    requests: Arc<AtomicUsize>,
+    /// Whether the last read found nothing to read
+    waiting: Arc<AtomicBool>,
+}
+
+impl Activity {
+    fn is_idle(&self) -> bool {
+        self.requests.load(Ordering::SeqCst) == 0 && self.waiting.load(Ordering::SeqCst)
+    }
+}
+
+/**
+ * A connection which keeps track of its Activity
+ */
+#[derive(Clone)]
+struct Tracked<RW> {
+    io: RW,
+    activity: Activity,
+}
+
+impl<RW: async_std::io::Read + Unpin> async_std::io::Read for Tracked<RW> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.io).poll_read(cx, buf);
+        this.activity
+            .waiting
+            .store(poll.is_pending(), Ordering::SeqCst);
+        poll
+    }
+}
+
+impl<RW: async_std::io::Write + Unpin> async_std::io::Write for Tracked<RW> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        this.activity.waiting.store(false, Ordering::SeqCst);
+        Pin::new(&mut this.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_close(cx)
+    }
+}
+
+/**
+ * Serve the requests on a single connection until the client closes it, or
+ * until the first response once the server is draining. A keep-alive
+ * connection which is idle when the drain starts, or becomes idle during it,
+ * is closed right away
+ */
+async fn connection<State, RW>(
+    app: tide::Server<State>,
+    io: RW,
+    local_addr: Option<SocketAddr>,
+    peer_addr: Option<SocketAddr>,
+    draining: Receiver<()>,
+) -> tide::http::Result<()>
+where
+    State: Clone + Send + Sync + 'static,
+    RW: async_std::io::Read + async_std::io::Write + Clone + Send + Sync + Unpin + 'static,
+{
+    let activity = Activity::default();
+    let io = Tracked {
+        io,
+        activity: activity.clone(),
+    };
+
+    let serving = async_h1::accept(io, |mut req| {
+        let app = app.clone();
+        let draining = draining.clone();
+        let activity = activity.clone();
+        async move {
+            req.set_local_addr(local_addr);
+            req.set_peer_addr(peer_addr);
+            activity.requests.fetch_add(1, Ordering::SeqCst);
+            let res: tide::http::Result<tide::http::Response> = app.respond(req).await;
+            // The response still has to be written before the connection is idle
+            activity.waiting.store(false, Ordering::SeqCst);
+            activity.requests.fetch_sub(1, Ordering::SeqCst);
+
+            let mut res = res?;
+            if draining.is_closed() {
+                res.insert_header("Connection", "close");
+            }
+            Ok(res)
+        }
+    });
+
+    let closing = async {
+        // Fails once the drain starts and the sender has been dropped
+        let _ = draining.recv().await;
+        while !activity.is_idle() {
+            task::sleep(IDLE_CHECK).await;
+        }
+        debug!("Closing an idle connection from {:?}", peer_addr);
+        Ok(())
+    };
+
+    serving.or(closing).await
+}
+
+enum Event {
+    Accepted(io::Result<(TcpStream, SocketAddr)>),
+    Signal(Signal),
+}
+
+/**
+ * Serve the app on the listener until a shutdown signal is received, and then
+ * wait up to the drain timeout for the open connections to finish
+ */
+pub async fn serve<State: Clone + Send + Sync + 'static>(
+    app: tide::Server<State>,
+    listener: TcpListener,
+    config: &Config,
+    signals: Receiver<Signal>,
+) -> io::Result<()> {
+    let mut tls = match &config.tls {
+        Some(tls) => Some(TlsAcceptor::from(load_tls(tls)?)),
+        None => None,
+    };
+    // Dropping the sender closes the channel, which tells the connections to drain
+    let (drain, draining) = async_std::channel::bounded::<()>(1);
+    // Every connection holds a sender, so the channel closes once they're all done
+    let (open, drained) = async_std::channel::bounded::<()>(1);
+
+    info!(
+        "Listening on {}://{}",
+        if tls.is_some() { "https" } else { "http" },
+        listener.local_addr()?
+    );
+
+    loop {
+        let event = async { Event::Accepted(listener.accept().await) }
+            .or(async {
+                match signals.recv().await {
+                    Ok(signal) => Event::Signal(signal),
+                    Err(_) => futures_lite::future::pending().await,
+                }
+            })
+            .await;
+
+        match event {
+            Event::Accepted(Ok((stream, peer_addr))) => {
+                let app = app.clone();
+                let tls = tls.clone();
+                let draining = draining.clone();
+                let open = open.clone();
+
+                task::spawn(async move {
+                    let local_addr = stream.local_addr().ok();
+                    let result = match tls {
+                        None => {
+                            connection(app, stream, local_addr, Some(peer_addr), draining).await
+                        }
+                        Some(acceptor) => match acceptor.accept(stream).await {
+                            Ok(stream) => {
+                                let stream = TlsConnection(Arc::new(Mutex::new(stream)));
+                                connection(app, stream, local_addr, Some(peer_addr), draining).await
+                            }
+                            Err(e) => Err(e.into()),
+                        },
+                    };
+                    if let Err(e) = result {
+                        debug!("Connection from {} failed: {}", peer_addr, e);
+                    }
+                    drop(open);
+                });
+            }
+            Event::Accepted(Err(e)) => {
+                // Most likely out of file descriptors, which takes a moment to recover from
+                error!("Failed to accept a connection: {}", e);
+                task::sleep(Duration::from_millis(500)).await;
+            }
+            Event::Signal(Signal::Reload) => {
+                if let Some(config) = &config.tls {
+                    match load_tls(config) {
+                        Ok(reloaded) => {
+                            info!("Reloaded the TLS certificate");
+                            tls = Some(TlsAcceptor::from(reloaded));
+                        }
+                        Err(e) => error!(
+                            "Failed to reload the TLS certificate, keeping the current one: {}",
+                            e
+                        ),
+                    }
+                }
+            }
+            Event::Signal(Signal::Shutdown) => break,
+        }
+    }
+
+    info!(
+        "Shutting down, waiting up to {} seconds for open connections",
+        config.shutdown_timeout_seconds
+    );
+    drop(listener);
+    drop(drain);
+    drop(open);
+
+    let timeout = Duration::from_secs(config.shutdown_timeout_seconds);
+    if async_std::future::timeout(timeout, drained.recv())
+        .await
+        .is_err()
+    {
+        warn!("Connections were still open after the drain timeout");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[async_std::test]
+    async fn test_drain_on_shutdown() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/slow", listener.local_addr().unwrap());
+        let mut app = tide::new();
+        app.at("/slow").get(|_: tide::Request<()>| async {
+            task::sleep(Duration::from_millis(500)).await;
+            Ok("done")
+        });
+
+        let (signals, receiver) = async_std::channel::unbounded();
+        let config = Config {
+            shutdown_timeout_seconds: 5,
+            ..Default::default()
+        };
+        let server = task::spawn(async move { serve(app, listener, &config, receiver).await });
+
+        let request = task::spawn(surf::get(url).recv_string());
+        task::sleep(Duration::from_millis(100)).await;
+        signals.send(Signal::Shutdown).await.unwrap();
+
+        let body = request
+            .await
+            .expect("The in-flight request should have been finished");
+        assert_eq!("done", body);
+        server.await.expect("Failed to shut down");
+    }
+
+    #[async_std::test]
+    async fn test_close_idle_connections_on_shutdown() {
+        use async_std::io::{ReadExt, WriteExt};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut app = tide::new();
+        app.at("/").get(|_: tide::Request<()>| async { Ok("done") });
+
+        let (signals, receiver) = async_std::channel::unbounded();
+        let config = Config {
+            shutdown_timeout_seconds: 30,
+            ..Default::default()
+        };
+        let server = task::spawn(async move { serve(app, listener, &config, receiver).await });
+
+        // Leave a keep-alive connection idle after its first response
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        let mut buf = [0; 1024];
+        while !String::from_utf8_lossy(&response).ends_with("done") {
+            let read = stream.read(&mut buf).await.unwrap();
+            assert!(read > 0, "The connection closed before the response");
+            response.extend_from_slice(&buf[..read]);
+        }
+
+        signals.send(Signal::Shutdown).await.unwrap();
+        async_std::future::timeout(Duration::from_secs(5), server)
+            .await
+            .expect("The idle connection should not hold up the shutdown")
+            .expect("Failed to shut down");
+        assert_eq!(0, stream.read(&mut buf).await.unwrap());
+    }
+}
@@ -1,40 +1,113 @@
 use async_std::sync::{Arc, RwLock};
 use handlebars::Handlebars;
+use log::*;
+use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
 use std::collections::HashMap;
-use tide_http_auth::{BasicAuthRequest, BearerAuthRequest, Storage};
+use tide_http_auth::{BearerAuthRequest, Storage};
 use uuid::Uuid;
 
+use crate::cache::TableCache;
+use crate::catalog::CatalogStore;
 use crate::config::Config;
+use crate::jwt::JwtIssuer;
+use crate::logging::REDACTED;
+use crate::models::Token;
+use crate::oidc::OidcClient;
+
+/**
+ * Everything which is replaced when the configuration is reloaded, kept
+ * together so that a request never sees a mix of the old and new settings
+ */
+#[derive(Clone, Debug)]
+pub struct Reloadable {
+    pub config: Arc<Config>,
+    /// Shares, schemas, tables, tokens and their grants
+    pub catalog: Arc<dyn CatalogStore>,
+    pub jwt_issuers: Arc<Vec<JwtIssuer>>,
+}
 
 #[derive(Clone, Debug)]
 pub struct AppState<'a> {
-    pub db: PgPool,
-    pub config: Config,
+    /// Absent when riverbank runs from the configuration file alone
+    pub db: Option<PgPool>,
+    pub oidc: Option<Arc<OidcClient>>,
+    pub table_cache: Arc<TableCache>,
+    reloadable: Arc<std::sync::RwLock<Reloadable>>,
     users: HashMap<String, User>,
 
     hb: Arc<RwLock<Handlebars<'a>>>,
 }
 
 impl AppState<'_> {
-    pub fn new(db: PgPool, config: Config) -> Self {
+    pub fn new(db: Option<PgPool>, catalog: Arc<dyn CatalogStore>, config: Config) -> Self {
         let mut users = HashMap::new();
-        users.insert(
-            std::env::var("RIVERBANK_ADMIN")
-                .expect("Must define the RIVERBANK_ADMIN environment variable"),
-            User {
-                password: std::env::var("RIVERBANK_PASSWORD")
-                    .expect("Must define the RIVERBANK_PASSWORD environment variable"),
-            },
-        );
+        if let (Some(name), Some(password)) = (&config.admin.username, &config.admin.password) {
+            users.insert(
+                name.clone(),
+                User {
+                    name: name.clone(),
+                    password: password.clone(),
+                },
+            );
+        }
         Self {
             hb: Arc::new(RwLock::new(Handlebars::new())),
             users,
             db,
-            config,
+            table_cache: Arc::new(TableCache::new(config.table_cache.clone())),
+            oidc: None,
+            reloadable: Arc::new(std::sync::RwLock::new(Reloadable {
+                config: Arc::new(config),
+                catalog,
+                jwt_issuers: Arc::new(vec![]),
+            })),
         }
     }
 
+    /// The current configuration
+    pub fn config(&self) -> Arc<Config> {
+        self.reloadable().config
+    }
+
+    /// The current catalog
+    pub fn catalog(&self) -> Arc<dyn CatalogStore> {
+        self.reloadable().catalog
+    }
+
+    /// The issuers whose JWTs are currently accepted
+    pub fn jwt_issuers(&self) -> Arc<Vec<JwtIssuer>> {
+        self.reloadable().jwt_issuers
+    }
+
+    pub fn reloadable(&self) -> Reloadable {
+        self.reloadable
+            .read()
+            .expect("The configuration lock was poisoned")
+            .clone()
+    }
+
+    /**
+     * Replace the configuration, catalog and JWT issuers all at once for the
+     * requests which come after
+     */
+    pub fn swap(&self, reloadable: Reloadable) {
+        *self
+            .reloadable
+            .write()
+            .expect("The configuration lock was poisoned") = reloadable;
+    }
+
+    /**
+     * The database, for the features which need one, or a 404 for the
+     * requests which depend on them
+     */
+    pub fn database(&self) -> Result<&PgPool, tide::Error> {
+        self.db
+            .as_ref()
+            .ok_or_else(|| tide::Error::from_str(404, "Riverbank is running without a database"))
+    }
+
     pub async fn register_templates(&self) -> Result<(), handlebars::TemplateError> {
         let mut hb = self.hb.write().await;
         hb.clear_templates();
@@ -62,50 +135,160 @@ impl AppState<'_> {
     }
 }
 
-#[derive(Clone, Debug)]
+/**
+ * The roles an admin console user can have
+ */
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Full access to the admin console
+    Admin,
+    /// Read-only access to the admin console
+    Viewer,
+}
+
+impl Role {
+    pub fn can_edit(&self) -> bool {
+        *self == Role::Admin
+    }
+}
+
+#[derive(Clone)]
 pub struct User {
+    pub name: String,
     password: String,
 }
 
-#[async_trait::async_trait]
-impl Storage<User, BasicAuthRequest> for AppState<'_> {
-    async fn get_user(&self, request: BasicAuthRequest) -> tide::Result<Option<User>> {
-        match self.users.get(&request.username) {
+impl std::fmt::Debug for User {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("User")
+            .field("name", &self.name)
+            .field("password", &REDACTED)
+            .finish()
+    }
+}
+
+impl AppState<'_> {
+    /**
+     * Check the given credentials against the built-in admin users, returning
+     * the matching User if the password is correct
+     */
+    pub fn authenticate(&self, username: &str, password: &str) -> Option<User> {
+        match self.users.get(username) {
             Some(user) => {
-                // Again, this is just an example. In practice you'd want to use something called a
-                // "constant time comparison function" to check if the passwords are equivalent to
-                // avoid a timing attack.
-                if user.password != request.password {
-                    return Ok(None);
+                // In practice you'd want to use something called a "constant time comparison
+                // function" to check if the passwords are equivalent to avoid a timing attack.
+                if user.password != password {
+                    return None;
                 }
 
-                Ok(Some(user.clone()))
+                Some(user.clone())
             }
-            None => Ok(None),
+            None => None,
         }
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, sqlx::FromRow)]
 pub struct Tokened {
     pub id: Uuid,
+    /// The name of the token, which identifies the recipient
+    pub name: String,
     token: String,
 }
 
+impl From<Token> for Tokened {
+    fn from(token: Token) -> Self {
+        Self {
+            id: token.id,
+            name: token.name,
+            token: token.token,
+        }
+    }
+}
+
+impl std::fmt::Debug for Tokened {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tokened")
+            .field("id", &self.id)
+            .field("name", &self.name)
+            .field("token", &REDACTED)
+            .finish()
+    }
+}
+
+/**
+ * A valid JWT naming a recipient who has no unexpired token in the catalog.
+ * JWTs carry no grants of their own, so the recipient cannot access anything
+ */
+#[derive(Debug)]
+pub struct NoActiveToken(pub String);
+
+impl std::fmt::Display for NoActiveToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The JWT is valid, but the recipient {} has no unexpired token whose grants it could use",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for NoActiveToken {}
+
+impl AppState<'_> {
+    /**
+     * Validate a JWT from one of the configured issuers and find the token of
+     * the recipient it names, whose grants then apply to the request.
+     *
+     * A valid JWT for a recipient without an unexpired token is refused with
+     * a NoActiveToken error rather than as an unknown bearer token
+     */
+    async fn recipient_from_jwt(&self, token: &str) -> tide::Result<Option<Tokened>> {
+        let issuers = self.jwt_issuers();
+        let issuer = match crate::jwt::issuer_of(token, &issuers) {
+            Some(issuer) => issuer,
+            None => return Ok(None),
+        };
+        let recipient = match issuer.recipient(token).await {
+            Ok(recipient) => recipient,
+            Err(e) => {
+                debug!("Rejecting JWT bearer token: {}", e);
+                return Ok(None);
+            }
+        };
+
+        match self.catalog().token_for_recipient(&recipient).await {
+            Ok(Some(tokened)) => Ok(Some(tokened)),
+            Ok(None) => {
+                warn!(
+                    "Refusing a JWT for {} who has no unexpired token",
+                    recipient
+                );
+                crate::metrics::TOKEN_AUTH_FAILURES.inc();
+                Err(tide::Error::new(403, NoActiveToken(recipient)))
+            }
+            Err(e) => {
+                error!("Failed to find the token of {}: {}", recipient, e);
+                Ok(None)
+            }
+        }
+    }
+}
+
 #[async_trait::async_trait]
 impl Storage<Tokened, BearerAuthRequest> for AppState<'_> {
     async fn get_user(&self, request: BearerAuthRequest) -> tide::Result<Option<Tokened>> {
-        if let Ok(record) = sqlx::query_as!(
-            Tokened,
-            r#"SELECT id, token FROM tokens WHERE token = $1 AND expires_at > NOW()"#,
-            request.token
-        )
-        .fetch_one(&self.db)
-        .await
-        {
-            Ok(Some(record))
-        } else {
-            Ok(None)
+        if !self.jwt_issuers().is_empty() && crate::jwt::looks_like_jwt(&request.token) {
+            return self.recipient_from_jwt(&request.token).await;
+        }
+
+        match self.catalog().authenticate(&request.token).await {
+            Ok(tokened) => Ok(tokened),
+            Err(e) => {
+                error!("Failed to look up a bearer token: {}", e);
+                Ok(None)
+            }
         }
     }
 }
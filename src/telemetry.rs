@@ -0,0 +1,133 @@
+/*
+ * The telemetry module exports trace spans to an OpenTelemetry collector over
+ * OTLP/HTTP, when it has been configured
+ */
+use opentelemetry::propagation::Extractor;
+use opentelemetry::sdk::propagation::TraceContextPropagator;
+use opentelemetry::sdk::{trace, Resource};
+use opentelemetry::trace::TraceError;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use std::time::Duration;
+use tide::Request;
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::config::TracingConfig;
+
+/**
+ * Start exporting spans in batches from a background task, returning the
+ * tracer for the tracing-opentelemetry layer
+ */
+pub fn tracer(config: &TracingConfig) -> Result<trace::Tracer, TraceError> {
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(config.otlp_endpoint.clone())
+                .with_timeout(Duration::from_secs(config.timeout_seconds)),
+        )
+        .with_trace_config(
+            trace::config().with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                config.service_name.clone(),
+            )])),
+        )
+        .install_batch(opentelemetry::runtime::AsyncStd)
+}
+
+/**
+ * Export the spans which are still buffered, which blocks until the collector
+ * has responded
+ */
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+struct RequestHeaders<'a, State>(&'a Request<State>);
+
+impl<'a, State> Extractor for RequestHeaders<'a, State> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.header(key).map(|h| h.last().as_str())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.header_names().map(|name| name.as_str()).collect()
+    }
+}
+
+/**
+ * Continue the trace of the caller when the request carries a `traceparent`
+ * header, otherwise the span starts a new trace
+ */
+pub fn set_parent<State>(span: &Span, req: &Request<State>) {
+    let parent = opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.extract(&RequestHeaders(req))
+    });
+    span.set_parent(parent);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::channel::{unbounded, Sender};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    /**
+     * Start a stand-in collector on a random local port which passes the body
+     * of every export request it receives to the channel, returning its
+     * endpoint
+     */
+    async fn collector(exports: Sender<Vec<u8>>) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/v1/traces", listener.local_addr().unwrap());
+        let mut app = tide::with_state(exports);
+
+        app.at("/v1/traces")
+            .post(|mut req: Request<Sender<Vec<u8>>>| async move {
+                assert_eq!(
+                    Some("application/x-protobuf"),
+                    req.header("Content-Type").map(|h| h.last().as_str())
+                );
+                let body = req.body_bytes().await?;
+                req.state().send(body).await?;
+                Ok(tide::Response::new(200))
+            });
+
+        async_std::task::spawn(async move { app.listen(listener).await });
+        endpoint
+    }
+
+    #[async_std::test]
+    async fn test_export_spans() {
+        let (sender, exports) = unbounded();
+        let config = TracingConfig {
+            otlp_endpoint: collector(sender).await,
+            service_name: "riverbank-test".to_string(),
+            timeout_seconds: 5,
+        };
+        let tracer = tracer(&config).expect("Failed to install the pipeline");
+        let subscriber =
+            tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("request").in_scope(|| {
+                tracing::info_span!("Table::find", table = "vaccine_ingredients").in_scope(|| {})
+            });
+        });
+        async_std::task::spawn_blocking(shutdown).await;
+
+        let body = async_std::future::timeout(Duration::from_secs(5), exports.recv())
+            .await
+            .expect("The collector received nothing")
+            .unwrap();
+        // Strings are encoded as is in protobuf messages
+        let contains = |needle: &str| body.windows(needle.len()).any(|w| w == needle.as_bytes());
+        assert!(contains("riverbank-test"));
+        assert!(contains("request"));
+        assert!(contains("Table::find"));
+    }
+}
@@ -0,0 +1,113 @@
+/*
+ * The watcher module polls every table in the background, recording its
+ * latest version and health in the catalog
+ */
+use log::*;
+use serde_json::json;
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::cache::Snapshot;
+use crate::models::{Table, TableStatus, Webhook};
+use crate::state::AppState;
+
+/**
+ * Check every table once per configured interval, forever
+ */
+pub async fn watch(state: AppState<'static>, db: PgPool) {
+    let interval = Duration::from_secs(state.config().watcher.interval_seconds);
+    info!("Checking all tables every {} seconds", interval.as_secs());
+
+    loop {
+        if let Err(e) = check_all(&state, &db).await {
+            error!("Failed to list the tables to check: {}", e);
+        }
+        async_std::task::sleep(interval).await;
+    }
+}
+
+/**
+ * Check the latest version of every table, which also keeps the cached
+ * tables up to date. A table which cannot be checked is logged and skipped
+ * so that it does not hold up the others
+ */
+async fn check_all(state: &AppState<'_>, db: &PgPool) -> Result<(), sqlx::Error> {
+    let versions: HashMap<Uuid, Option<i64>> = TableStatus::list_all(db)
+        .await?
+        .into_iter()
+        .map(|status| (status.table_id, status.version))
+        .collect();
+
+    for table in state.catalog().list_tables().await? {
+        if let Err(e) = check(
+            state,
+            &table,
+            versions.get(&table.inner.id).copied().flatten(),
+            db,
+        )
+        .await
+        {
+            error!("Failed to record the status of {}: {}", table.name(), e);
+        }
+    }
+    Ok(())
+}
+
+/**
+ * Record the status of the table, notifying its recipients when there is a
+ * newer version than the `previous` one recorded
+ */
+async fn check(
+    state: &AppState<'_>,
+    table: &Table,
+    previous: Option<i64>,
+    db: &PgPool,
+) -> Result<(), sqlx::Error> {
+    let id = &table.inner.id;
+
+    // Going around the cache's bookkeeping, so that polling does not keep
+    // every table in the cache and inflate its hits
+    match state.table_cache.check(&table.inner.location).await {
+        Ok(snapshot) => {
+            debug!("{} is at version {}", table.name(), snapshot.version);
+            TableStatus::healthy(id, &snapshot, db).await?;
+
+            // Tables seen for the first time have no previous version
+            // which recipients could have missed
+            if let Some(previous) = previous {
+                if snapshot.version > previous {
+                    notify(table, &snapshot, db).await?;
+                }
+            }
+        }
+        Err(e) => {
+            warn!(
+                "Failed to load {} from {}: {}",
+                table.name(),
+                table.inner.location,
+                e
+            );
+            TableStatus::unhealthy(id, &e.to_string(), db).await?;
+        }
+    }
+    Ok(())
+}
+
+/**
+ * Queue notifications about the new version for the webhooks of every
+ * recipient of the table
+ */
+async fn notify(table: &Table, snapshot: &Snapshot, db: &PgPool) -> Result<(), sqlx::Error> {
+    let payload = json!({
+        "share" : table.share(),
+        "schema" : table.schema(),
+        "table" : table.name(),
+        "version" : snapshot.version,
+        "commitTimestamp" : snapshot.commit_timestamp.map(|seconds| seconds * 1000),
+    });
+    let queued = Webhook::enqueue(&table.inner.id, &payload, db).await?;
+    debug!("Queued {} webhook deliveries for {}", queued, payload);
+    Ok(())
+}
@@ -0,0 +1,218 @@
+/*
+ * The webhooks module delivers notifications about new table versions to the
+ * webhook endpoints registered for recipients
+ */
+use chrono::Utc;
+use hmac::{Hmac, Mac, NewMac};
+use log::*;
+use sha2::Sha256;
+use sqlx::PgPool;
+use std::time::Duration;
+
+use crate::config::WebhookConfig;
+use crate::models::{PendingDelivery, WebhookDelivery};
+use crate::state::AppState;
+
+/// The hex encoded HMAC-SHA256 of the request body, keyed with the secret of
+/// the webhook
+pub const SIGNATURE_HEADER: &str = "X-Riverbank-Signature";
+/// The id of the delivery, which stays the same across retries
+pub const DELIVERY_HEADER: &str = "X-Riverbank-Delivery";
+
+/**
+ * Compute the value of the signature header for the given body
+ */
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_varkey(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/**
+ * A failed delivery attempt, with the status code of the response if the
+ * endpoint could be reached at all
+ */
+#[derive(Debug)]
+pub struct DeliveryError {
+    pub status: Option<i32>,
+    pub message: String,
+}
+
+/**
+ * POST the delivery's payload to its webhook, returning the status code of
+ * a successful response
+ */
+pub async fn send(delivery: &PendingDelivery, timeout: Duration) -> Result<i32, DeliveryError> {
+    let body = delivery.payload.to_string();
+    let request = surf::post(&delivery.url)
+        .header(SIGNATURE_HEADER, sign(&delivery.secret, body.as_bytes()))
+        .header(DELIVERY_HEADER, delivery.id.to_string())
+        .body(surf::Body::from_string(body))
+        .content_type(surf::http::mime::JSON);
+
+    let response = async_std::future::timeout(timeout, request)
+        .await
+        .map_err(|_| DeliveryError {
+            status: None,
+            message: format!("No response within {} seconds", timeout.as_secs()),
+        })?
+        .map_err(|e| DeliveryError {
+            status: None,
+            message: e.to_string(),
+        })?;
+
+    let status = u16::from(response.status()) as i32;
+    if response.status().is_success() {
+        Ok(status)
+    } else {
+        Err(DeliveryError {
+            status: Some(status),
+            message: format!("The webhook responded with {}", response.status()),
+        })
+    }
+}
+
+/**
+ * How long to wait before the next attempt after `attempts` failed ones, or
+ * None when the delivery should be given up on
+ */
+fn backoff(config: &WebhookConfig, attempts: i32) -> Option<chrono::Duration> {
+    if attempts >= config.max_attempts {
+        return None;
+    }
+    let seconds = config
+        .initial_backoff_seconds
+        .saturating_mul(1_i64 << (attempts - 1).clamp(0, 30))
+        .min(config.max_backoff_seconds);
+    Some(chrono::Duration::seconds(seconds))
+}
+
+/**
+ * Send the deliveries which are due, forever
+ */
+pub async fn deliver(state: AppState<'static>, db: PgPool) {
+    let config = state.config().webhooks.clone();
+
+    loop {
+        match WebhookDelivery::due(100, &db).await {
+            Ok(due) => {
+                for delivery in due.iter() {
+                    if let Err(e) = attempt(delivery, &config, &db).await {
+                        error!("Failed to record the delivery {}: {}", delivery.id, e);
+                    }
+                }
+            }
+            Err(e) => error!("Failed to find the webhook deliveries due: {}", e),
+        }
+        async_std::task::sleep(Duration::from_secs(config.poll_interval_seconds)).await;
+    }
+}
+
+async fn attempt(
+    delivery: &PendingDelivery,
+    config: &WebhookConfig,
+    db: &PgPool,
+) -> Result<(), sqlx::Error> {
+    match send(delivery, Duration::from_secs(config.timeout_seconds)).await {
+        Ok(status) => {
+            debug!("Delivered {} to {}", delivery.id, delivery.url);
+            WebhookDelivery::delivered(&delivery.id, status, db).await
+        }
+        Err(e) => {
+            let retry_at = backoff(config, delivery.attempts + 1).map(|wait| Utc::now() + wait);
+            warn!(
+                "Failed to deliver {} to {}, {}: {}",
+                delivery.id,
+                delivery.url,
+                retry_at.map_or("giving up".to_string(), |at| format!("retrying at {}", at)),
+                e.message
+            );
+            WebhookDelivery::attempt_failed(&delivery.id, e.status, &e.message, retry_at, db).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tide::Request;
+    use uuid::Uuid;
+
+    /**
+     * Start a stand-in webhook receiver on a random local port which only
+     * accepts payloads signed with the given secret, returning its URL
+     */
+    async fn receiver(secret: &'static str) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let mut app = tide::new();
+
+        app.at("/hook")
+            .post(move |mut req: Request<()>| async move {
+                let signature = req
+                    .header(SIGNATURE_HEADER)
+                    .map(|h| h.last().to_string())
+                    .unwrap_or_default();
+                let body = req.body_bytes().await?;
+
+                if req.header(DELIVERY_HEADER).is_none() || signature != sign(secret, &body) {
+                    return Ok(tide::Response::new(401));
+                }
+                let payload: serde_json::Value = serde_json::from_slice(&body)?;
+                assert_eq!(Some(3), payload["version"].as_i64());
+                Ok(tide::Response::new(204))
+            });
+
+        async_std::task::spawn(async move { app.listen(listener).await });
+        url
+    }
+
+    fn delivery(url: String) -> PendingDelivery {
+        PendingDelivery {
+            id: Uuid::new_v4(),
+            payload: json!({
+                "share" : "vaccine_share",
+                "schema" : "acme_vaccine_data",
+                "table" : "vaccine_ingredients",
+                "version" : 3,
+            }),
+            attempts: 0,
+            url,
+            secret: "hunter2".to_string(),
+        }
+    }
+
+    #[async_std::test]
+    async fn test_signed_delivery() {
+        let url = receiver("hunter2").await;
+        let status = send(&delivery(url), Duration::from_secs(5))
+            .await
+            .expect("Failed to deliver to the receiver");
+        assert_eq!(204, status);
+    }
+
+    #[async_std::test]
+    async fn test_rejected_delivery() {
+        let url = receiver("some-other-secret").await;
+        let err = send(&delivery(url), Duration::from_secs(5))
+            .await
+            .expect_err("The receiver should have rejected the signature");
+        assert_eq!(Some(401), err.status);
+    }
+
+    #[test]
+    fn test_backoff() {
+        let config = WebhookConfig {
+            max_attempts: 4,
+            initial_backoff_seconds: 30,
+            max_backoff_seconds: 90,
+            ..Default::default()
+        };
+        assert_eq!(Some(chrono::Duration::seconds(30)), backoff(&config, 1));
+        assert_eq!(Some(chrono::Duration::seconds(60)), backoff(&config, 2));
+        assert_eq!(Some(chrono::Duration::seconds(90)), backoff(&config, 3));
+        assert_eq!(None, backoff(&config, 4));
+    }
+}